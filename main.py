@@ -1,190 +1,4311 @@
 #!/usr/bin/env python3
 
+import argparse
+import base64
+import collections
 import concurrent.futures
+import contextlib
+import copy
+import dataclasses
 from concurrent.futures import ThreadPoolExecutor
-from datetime import datetime
+from datetime import datetime, time as dt_time, timedelta, timezone
+import functools
+import gzip
+import hashlib
+import hmac
+import json
 import logging
+import os
 import random
+import re
+import shlex
+import signal
 import string
+import sys
+import threading
+import time
+import types
+import uuid
 import yaml
+import zoneinfo
 
 import github
+from github.GithubRetry import GithubRetry
 
-from flask import Flask
+from flask import Flask, g, jsonify, request
+from flask.logging import default_handler
 from flask_apscheduler import APScheduler
 from github_webhook import Webhook
 
+import keystoneauth1.exceptions
 import openstack
 import requests
 
-with open("config.yml", "r", encoding="utf-8") as fd:
-    CFG = yaml.safe_load(fd)
-CLOUD = openstack.connect(cloud=CFG["openstack"]["cloud"])
 
-auth = github.Auth.Token(CFG["github"]["token"])
-g = github.Github(auth=auth)
+class Error(Exception):
+    """Base class for the errors raised by the controller.
+
+    The category tells apart the failures callers may want to handle
+    differently, such as "auth", "rate_limit", "quota", "not_found", "server",
+    "timeout", "unavailable" or "other".
+    """
+
+    category = "other"
+
+
+class ConfigError(Error):
+    category = "config"
+
+
+class CloudConfigError(Error):
+    category = "cloud_config"
+
+
+class GitHubError(Error):
+    def __init__(self, message: str, status: int | None = None):
+        super().__init__(message)
+        self.status = status
+        self.category = categorize_http_error(status, message)
+
+
+class IncompleteInventoryError(GitHubError):
+    """Raised when the runners of an organization could not all be listed."""
+
+    def __init__(self, message: str, status: int | None = None):
+        super().__init__(message, status)
+        self.category = "incomplete"
+
+
+class OpenStackError(Error):
+    def __init__(self, message: str, status: int | None = None):
+        super().__init__(message)
+        self.status = status
+        self.category = categorize_http_error(status, message)
+
+
+class CreateTimeoutError(OpenStackError):
+    """Raised when a request to create a server is still running after a while."""
+
+    def __init__(self, message: str):
+        super().__init__(message)
+        self.category = "timeout"
+
+
+def categorize_http_error(status: int | None, message: str) -> str:
+    message = message.lower()
+    if status == 429 or (status == 403 and "rate limit" in message):
+        return "rate_limit"
+    if status == 403 and "quota" in message:
+        return "quota"
+    if status in (401, 403):
+        return "auth"
+    if status == 404:
+        return "not_found"
+    if status is not None and status >= 500:
+        return "server"
+    return "other"
+
+
+@contextlib.contextmanager
+def github_errors():
+    try:
+        yield
+    except github.GithubException as e:
+        raise GitHubError(str(e), e.status) from e
+    except requests.RequestException as e:
+        raise GitHubError(str(e)) from e
+
+
+@contextlib.contextmanager
+def openstack_errors():
+    try:
+        yield
+    except openstack.exceptions.SDKException as e:
+        raise OpenStackError(str(e), getattr(e, "status_code", None)) from e
+    except keystoneauth1.exceptions.ConnectTimeout as e:
+        error = OpenStackError("timed out talking to OpenStack: %s" % e)
+        error.category = "timeout"
+        raise error from e
+    except keystoneauth1.exceptions.ClientException as e:
+        raise OpenStackError(str(e)) from e
+
+
+# NOTE: Nova rejects user data which is larger than this once base64 encoded.
+MAX_USER_DATA_SIZE = 65535
+
+# NOTE: Servers and runners are named after their prefix followed by this many
+#       random lowercase letters.
+NAME_SUFFIX_LENGTH = 5
+
+START_SCRIPT = "scripts/start.sh"
+BOOT_LOG = "/var/log/gha-start.log"
+JIT_CONFIG_PLACEHOLDER = "___JIT_CONFIG___"
+CONFIG_ARGS_PLACEHOLDER = "___CONFIG_ARGS___"
+
+# NOTE: Nova limits the length of server tags and doesn't allow commas or
+#       slashes in them, since they are used in its tags API and queries.
+MAX_TAG_LENGTH = 60
+DEBUG_HOLD_TAG = "gha-debug-hold"
+
+# NOTE: Nova limits both the description of servers and the values of their
+#       metadata to this length.
+MAX_DESCRIPTION_LENGTH = 255
+DEFAULT_DESCRIPTION = "GitHub Actions runner for pool '{pool}' (labels: {labels})"
+
+# NOTE: Only the end of the console output of a failed server is logged, it
+#       is stored in full when 'console_log_dir' is set.
+CONSOLE_LOG_LINES = 100
+
+# NOTE: Fields of the request creating a server which the controller sets
+#       itself, so they can't be overridden with 'extra_server_fields'.  A
+#       description set there is still used as is, as it was before pools
+#       had their own 'instance.description'.
+RESERVED_SERVER_FIELDS = (
+    "name",
+    "image",
+    "flavor",
+    "key_name",
+    "userdata",
+    "config_drive",
+    "meta",
+    "volumes",
+    "network",
+    "nics",
+    "scheduler_hints",
+    "tags",
+    "wait",
+    "timeout",
+)
+
+# NOTE: Labels which can be computed from the servers of a pool, with the
+#       prefix of their value.
+COMPUTED_LABELS = {"availability_zone": "az", "flavor": "flavor", "image": "image"}
+
+WEEKDAYS = ("mon", "tue", "wed", "thu", "fri", "sat", "sun")
+
+
+def get_deployment_id(cfg: dict) -> str:
+    return cfg.get("deployment_id", cfg.get("resource_prefix", "gha-"))
+
+
+def get_managed_tag(cfg: dict) -> str:
+    return "gha-managed-by:%s" % get_deployment_id(cfg)
+
+
+def normalize_label(label: str) -> str:
+    """Normalize a label for comparisons, since GitHub ignores its case."""
+    return label.strip().lower()
+
+
+def is_allowed_by_label_policy(cfg: dict, labels) -> bool:
+    """Check if runners or jobs with some labels may be acted upon.
+
+    With an ``allow`` list in the ``label_policy``, one of the labels must be
+    in it, and none of them may be in its ``deny`` list.
+    """
+    policy = cfg.get("label_policy") or {}
+    labels = {normalize_label(label) for label in labels}
+    allow = {normalize_label(label) for label in policy.get("allow") or []}
+    deny = {normalize_label(label) for label in policy.get("deny") or []}
+    return (not allow or bool(labels & allow)) and not labels & deny
+
+
+def parse_clock(value) -> dt_time | None:
+    """Parse a time of day such as ``08:30``, or return None if it is invalid."""
+    try:
+        return datetime.strptime(value, "%H:%M").time()
+    except (TypeError, ValueError):
+        return None
+
+
+def get_min_ready(pool: dict, now: datetime) -> int:
+    """Return the min_ready of a pool at some point in time.
+
+    This is the ``min_ready`` of the first window of the pool's ``schedule``
+    which it falls in, or the pool's own ``min_ready`` outside of them.  A
+    window which ends before it starts spans midnight, from the days it
+    starts on.
+    """
+    schedule = pool.get("schedule")
+    if not schedule:
+        return pool["min_ready"]
+
+    local = now.astimezone(zoneinfo.ZoneInfo(schedule.get("timezone", "UTC")))
+    today = WEEKDAYS[local.weekday()]
+    yesterday = WEEKDAYS[(local.weekday() - 1) % 7]
+    clock = local.time()
+    for window in schedule["windows"]:
+        start, end = parse_clock(window["start"]), parse_clock(window["end"])
+        days = window.get("days", WEEKDAYS)
+        if start < end:
+            inside = today in days and start <= clock < end
+        else:
+            inside = (today in days and clock >= start) or (
+                yesterday in days and clock < end
+            )
+        if inside:
+            return window["min_ready"]
+    return pool["min_ready"]
+
+
+def get_networks(pool: dict) -> list:
+    """Return the networks of a pool, each either a name (or id) or a mapping.
+
+    Mappings have the ``network`` along with the ``subnet`` or ``fixed_ip`` to
+    use in it, or an existing ``port`` on their own.
+    """
+    networks = pool["instance"]["network"]
+    return networks if isinstance(networks, list) else [networks]
+
+
+def is_valid_tag(tag) -> bool:
+    return (
+        isinstance(tag, str)
+        and 0 < len(tag) <= MAX_TAG_LENGTH
+        and "," not in tag
+        and "/" not in tag
+    )
+
+
+def is_uuid(value: str) -> bool:
+    try:
+        uuid.UUID(value)
+    except ValueError:
+        return False
+    return True
+
+
+def load_config(path: str, overrides: dict | None = None) -> dict:
+    with open(path, "r", encoding="utf-8") as fd:
+        cfg = yaml.safe_load(fd)
+    apply_env_overrides(cfg, os.environ)
+    cfg.update(overrides or {})
+    apply_pool_defaults(cfg)
+    resolve_secrets(cfg, os.environ)
+    validate_config(cfg)
+    return cfg
+
+
+# NOTE: Keys whose value is a secret, which are never parsed as YAML when
+#       given in the environment so that they are kept as is.
+SECRET_KEYS = ("token", "secret", "admin_token")
+
+
+def apply_env_overrides(cfg: dict, environ: dict, prefix: str = "GHA_"):
+    """Override configuration values from environment variables.
+
+    The variable name is the path to the value with its segments joined by
+    double underscores, so ``GHA_GITHUB__TOKEN`` overrides ``github.token`` and
+    ``GHA_POOLS__0__MIN_READY`` overrides ``min_ready`` of the first pool.  The
+    value is parsed as YAML so that numbers and booleans keep their types,
+    except for secrets which are always kept as strings.
+    """
+    for name in sorted(environ):
+        if not name.startswith(prefix) or name == prefix + "CONFIG":
+            continue
+
+        path = name[len(prefix) :].lower().split("__")
+        try:
+            node = cfg
+            for segment in path[:-1]:
+                if isinstance(node, list):
+                    node = node[int(segment)]
+                else:
+                    node = node.setdefault(segment, {})
+
+            value = environ[name]
+            if path[-1] not in SECRET_KEYS:
+                value = yaml.safe_load(value)
+            if isinstance(node, list):
+                node[int(path[-1])] = value
+            else:
+                node[path[-1]] = value
+        except (AttributeError, IndexError, TypeError, ValueError, yaml.YAMLError):
+            raise ConfigError("invalid configuration override '%s'" % name) from None
+
+
+def apply_pool_defaults(cfg: dict):
+    """Fill in the pools with the values of the ``defaults`` section.
+
+    Mappings such as ``instance`` and ``runner`` are merged key by key, with
+    the values of the pool taking precedence, while any other value of the
+    pool (including lists) replaces the default altogether.
+    """
+    defaults = cfg.get("defaults") or {}
+    if not isinstance(defaults, dict):
+        raise ConfigError("'defaults' must be a mapping")
+
+    if isinstance(cfg.get("pools"), list):
+        cfg["pools"] = [
+            merge_defaults(defaults, pool) if isinstance(pool, dict) else pool
+            for pool in cfg["pools"]
+        ]
+
+
+def merge_defaults(defaults: dict, values: dict) -> dict:
+    merged = copy.deepcopy(defaults)
+    for key, value in values.items():
+        if isinstance(value, dict) and isinstance(merged.get(key), dict):
+            merged[key] = merge_defaults(merged[key], value)
+        else:
+            merged[key] = value
+    return merged
+
+
+def resolve_secrets(cfg: dict, environ: dict):
+    """Read secrets given as ``<key>_file`` or ``<key>_env`` into their key.
+
+    This applies to the tokens of the GitHub organizations and of the admin
+    endpoints, and to the webhook secret.  Files have their trailing newlines
+    stripped, and a missing file or environment variable is an error.
+    """
+    sections = [("github", cfg.get("github"), "token")]
+    for name, github_cfg in (cfg.get("github_orgs") or {}).items():
+        sections.append(("github_orgs." + name, github_cfg, "token"))
+    sections.append(("webhook", cfg.get("webhook"), "secret"))
+    sections.append(("admin", cfg.get("admin"), "token"))
+
+    for section, section_cfg, key in sections:
+        if not isinstance(section_cfg, dict):
+            continue
+
+        path = section_cfg.pop(key + "_file", None)
+        variable = section_cfg.pop(key + "_env", None)
+        values = (section_cfg.get(key), path, variable)
+        if sum(value is not None for value in values) > 1:
+            raise ConfigError(
+                "only one of '%s.%s', '%s.%s_file' and '%s.%s_env' can be set"
+                % (section, key, section, key, section, key)
+            )
+
+        if path is not None:
+            try:
+                with open(path, "r", encoding="utf-8") as fd:
+                    section_cfg[key] = fd.read().rstrip("\r\n")
+            except OSError as e:
+                raise ConfigError(
+                    "failed to read '%s.%s_file': %s" % (section, key, e)
+                ) from e
+        elif variable is not None:
+            if variable not in environ:
+                raise ConfigError(
+                    "'%s.%s_env': %s is not set" % (section, key, variable)
+                )
+            section_cfg[key] = environ[variable]
+
+
+def validate_config(cfg: dict):
+    for section in ("github", "openstack", "pools"):
+        if not cfg.get(section):
+            raise ConfigError("missing '%s' section" % section)
+
+    for name, github_cfg in get_github_configs(cfg).items():
+        for key in ("org", "token"):
+            if not (github_cfg or {}).get(key):
+                section = "github" if name == "default" else "github_orgs." + name
+                raise ConfigError("missing '%s.%s'" % (section, key))
+        threshold = (github_cfg or {}).get("rate_limit_threshold", 0)
+        if not isinstance(threshold, int) or threshold < 0:
+            section = "github" if name == "default" else "github_orgs." + name
+            raise ConfigError(
+                "'%s.rate_limit_threshold' must be a non-negative integer" % section
+            )
+        if (github_cfg or {}).get("registration", "jit") not in ("jit", "token"):
+            section = "github" if name == "default" else "github_orgs." + name
+            raise ConfigError("'%s.registration' must be 'jit' or 'token'" % section)
+        for key in ("url", "api_url"):
+            if not isinstance((github_cfg or {}).get(key, ""), str):
+                section = "github" if name == "default" else "github_orgs." + name
+                raise ConfigError("'%s.%s' must be a URL" % (section, key))
+
+    if not cfg["openstack"].get("cloud"):
+        raise ConfigError("missing 'openstack.cloud'")
+
+    concurrency = cfg["openstack"].get("concurrency", 8)
+    if not isinstance(concurrency, int) or concurrency < 1:
+        raise ConfigError("'openstack.concurrency' must be a positive integer")
+
+    network_sweep_limit = cfg["openstack"].get("network_sweep_limit")
+    if network_sweep_limit is not None and (
+        not isinstance(network_sweep_limit, int) or network_sweep_limit < 1
+    ):
+        raise ConfigError(
+            "'openstack.network_sweep_limit' must be a positive integer"
+        )
+
+    region = cfg["openstack"].get("region")
+    if region is not None and (not isinstance(region, str) or not region):
+        raise ConfigError("'openstack.region' must be a non-empty string")
+
+    name_discriminator = cfg["openstack"].get("name_discriminator")
+    if name_discriminator is not None and (
+        not isinstance(name_discriminator, str)
+        or re.fullmatch("[a-z0-9]{1,8}", name_discriminator) is None
+    ):
+        raise ConfigError(
+            "'openstack.name_discriminator' must be 1 to 8 lowercase letters "
+            "or digits"
+        )
+
+    api_timeout = cfg["openstack"].get("api_timeout", 60)
+    if not isinstance(api_timeout, (int, float)) or api_timeout <= 0:
+        raise ConfigError("'openstack.api_timeout' must be a positive number")
+
+    create_timeout_secs = cfg["openstack"].get("create_timeout_secs")
+    if create_timeout_secs is not None and (
+        not isinstance(create_timeout_secs, (int, float)) or create_timeout_secs <= 0
+    ):
+        raise ConfigError("'openstack.create_timeout_secs' must be a positive number")
+
+    failure_threshold = cfg["openstack"].get("failure_threshold", 3)
+    if not isinstance(failure_threshold, int) or failure_threshold < 1:
+        raise ConfigError("'openstack.failure_threshold' must be a positive integer")
+
+    max_backoff_secs = cfg["openstack"].get("max_backoff_secs", 600)
+    if not isinstance(max_backoff_secs, (int, float)) or max_backoff_secs <= 0:
+        raise ConfigError("'openstack.max_backoff_secs' must be a positive number")
+
+    if not isinstance(cfg["openstack"].get("filter_by_tag", False), bool):
+        raise ConfigError("'openstack.filter_by_tag' must be a boolean")
+
+    cooldown_secs = cfg.get("cooldown_secs", 0)
+    if not isinstance(cooldown_secs, (int, float)) or cooldown_secs < 0:
+        raise ConfigError("'cooldown_secs' must be a non-negative number")
+
+    jitter_secs = cfg.get("jitter_secs", 0)
+    if not isinstance(jitter_secs, (int, float)) or not 0 <= jitter_secs < 30:
+        raise ConfigError("'jitter_secs' must be a number between 0 and 30")
+
+    for key in ("pool_concurrency", "spawn_concurrency", "delete_concurrency"):
+        value = cfg.get(key, 4)
+        if not isinstance(value, int) or value < 1:
+            raise ConfigError("'%s' must be a positive integer" % key)
+
+    for key in ("delivery_cache_size", "delivery_cache_ttl"):
+        value = (cfg.get("webhook") or {}).get(key, 0)
+        if not isinstance(value, int) or value < 0:
+            raise ConfigError("'webhook.%s' must be a non-negative integer" % key)
+
+    queued_job_ttl_secs = cfg.get("queued_job_ttl_secs", 3600)
+    if not isinstance(queued_job_ttl_secs, (int, float)) or queued_job_ttl_secs <= 0:
+        raise ConfigError("'queued_job_ttl_secs' must be a positive number")
+
+    drain_timeout_secs = cfg.get("drain_timeout_secs", 60)
+    if not isinstance(drain_timeout_secs, (int, float)) or drain_timeout_secs <= 0:
+        raise ConfigError("'drain_timeout_secs' must be a positive number")
+
+    registration_timeout_secs = cfg.get("registration_timeout_secs", 600)
+    if (
+        not isinstance(registration_timeout_secs, (int, float))
+        or registration_timeout_secs <= 0
+    ):
+        raise ConfigError("'registration_timeout_secs' must be a positive number")
+
+    offline_cycles = cfg.get("offline_cycles", 1)
+    if not isinstance(offline_cycles, int) or offline_cycles < 1:
+        raise ConfigError("'offline_cycles' must be a positive integer")
+
+    runner_min_age_secs = cfg.get("runner_min_age_secs", 0)
+    if not isinstance(runner_min_age_secs, (int, float)) or runner_min_age_secs < 0:
+        raise ConfigError("'runner_min_age_secs' must be a non-negative number")
+
+    full_reconcile_every = cfg.get("full_reconcile_every")
+    if full_reconcile_every is not None and (
+        not isinstance(full_reconcile_every, int) or full_reconcile_every < 1
+    ):
+        raise ConfigError("'full_reconcile_every' must be a positive integer")
+
+    console_log_dir = cfg.get("console_log_dir")
+    if console_log_dir is not None and (
+        not isinstance(console_log_dir, str) or not console_log_dir
+    ):
+        raise ConfigError("'console_log_dir' must be a non-empty string")
+
+    keep_failed_instances_secs = cfg.get("keep_failed_instances_secs", 0)
+    if (
+        not isinstance(keep_failed_instances_secs, (int, float))
+        or keep_failed_instances_secs < 0
+    ):
+        raise ConfigError("'keep_failed_instances_secs' must be a non-negative number")
+
+    threshold = cfg.get("delete_failure_threshold")
+    if threshold is not None and (not isinstance(threshold, int) or threshold < 1):
+        raise ConfigError("'delete_failure_threshold' must be a positive integer")
+
+    starved_threshold = cfg.get("starved_threshold", 3)
+    if not isinstance(starved_threshold, int) or starved_threshold < 1:
+        raise ConfigError("'starved_threshold' must be a positive integer")
+
+    max_total_instances = cfg.get("max_total_instances")
+    if max_total_instances is not None and (
+        not isinstance(max_total_instances, int) or max_total_instances < 0
+    ):
+        raise ConfigError("'max_total_instances' must be a non-negative integer")
+
+    resource_prefix = cfg.get("resource_prefix", "gha-")
+    if not isinstance(resource_prefix, str) or not resource_prefix:
+        raise ConfigError("'resource_prefix' must be a non-empty string")
+
+    deployment_id = cfg.get("deployment_id", resource_prefix)
+    if not isinstance(deployment_id, str) or not deployment_id:
+        raise ConfigError("'deployment_id' must be a non-empty string")
+    if not is_valid_tag(get_managed_tag(cfg)):
+        raise ConfigError("'deployment_id' cannot be used in a server tag")
+
+    default_labels = cfg.get("default_labels", [])
+    if not isinstance(default_labels, list) or not all(
+        isinstance(label, str) and label.strip() for label in default_labels
+    ):
+        raise ConfigError("'default_labels' must be a list of non-empty strings")
+
+    if not isinstance(cfg.get("notifications") or {}, dict):
+        raise ConfigError("'notifications' must be a mapping")
+
+    label_policy = cfg.get("label_policy") or {}
+    if not isinstance(label_policy, dict):
+        raise ConfigError("'label_policy' must be a mapping")
+    for key in ("allow", "deny"):
+        value = label_policy.get(key, [])
+        if not isinstance(value, list) or not all(
+            isinstance(label, str) and label.strip() for label in value
+        ):
+            raise ConfigError(
+                "'label_policy.%s' must be a list of non-empty strings" % key
+            )
+
+    names = set()
+    for idx, pool in enumerate(cfg["pools"]):
+        runner = pool.get("runner") or {}
+        instance = pool.get("instance") or {}
+
+        github_name = runner.get("github", "default")
+        if github_name not in get_github_configs(cfg):
+            raise ConfigError(
+                "pools[%d]: unknown GitHub organization '%s'" % (idx, github_name)
+            )
+
+        label = runner.get("label")
+        if not isinstance(label, str) or not label.strip():
+            raise ConfigError("pools[%d]: missing 'runner.label'" % idx)
+        if not is_allowed_by_label_policy(cfg, [label, *default_labels]):
+            raise ConfigError(
+                "pools[%d]: 'runner.label' is not allowed by 'label_policy'" % idx
+            )
+
+        name = pool.get("name", label)
+        if not isinstance(name, str) or not name.strip():
+            raise ConfigError("pools[%d]: 'name' must be a non-empty string" % idx)
+        if name in names:
+            raise ConfigError("pools[%d]: duplicate pool name '%s'" % (idx, name))
+        names.add(name)
+
+        weight = pool.get("weight", 1)
+        if not isinstance(weight, int) or weight < 1:
+            raise ConfigError("pools[%d]: 'weight' must be a positive integer" % idx)
+
+        min_ready = pool.get("min_ready")
+        if not isinstance(min_ready, int) or min_ready < 0:
+            raise ConfigError(
+                "pools[%d]: 'min_ready' must be a non-negative integer" % idx
+            )
+
+        min_ready_instances = pool.get("min_ready_instances", 0)
+        if not isinstance(min_ready_instances, int) or min_ready_instances < 0:
+            raise ConfigError(
+                "pools[%d]: 'min_ready_instances' must be a non-negative integer"
+                % idx
+            )
+
+        max_age_secs = pool.get("max_age_secs")
+        if max_age_secs is not None and (
+            not isinstance(max_age_secs, (int, float)) or max_age_secs <= 0
+        ):
+            raise ConfigError(
+                "pools[%d]: 'max_age_secs' must be a positive number" % idx
+            )
+
+        max_ready = pool.get("max_ready")
+        if max_ready is not None and (
+            not isinstance(max_ready, int) or max_ready < min_ready
+        ):
+            raise ConfigError(
+                "pools[%d]: 'max_ready' must be an integer not under 'min_ready'"
+                % idx
+            )
+
+        max_concurrent_builds = pool.get("max_concurrent_builds")
+        if max_concurrent_builds is not None and (
+            not isinstance(max_concurrent_builds, int)
+            or max_concurrent_builds < max(min_ready, 1)
+        ):
+            raise ConfigError(
+                "pools[%d]: 'max_concurrent_builds' must be a positive integer not "
+                "under 'min_ready'" % idx
+            )
+
+        schedule = pool.get("schedule")
+        if schedule is not None:
+            if not isinstance(schedule, dict):
+                raise ConfigError("pools[%d]: 'schedule' must be a mapping" % idx)
+            try:
+                zoneinfo.ZoneInfo(schedule.get("timezone", "UTC"))
+            except (TypeError, ValueError, zoneinfo.ZoneInfoNotFoundError):
+                raise ConfigError(
+                    "pools[%d]: unknown 'schedule.timezone' '%s'"
+                    % (idx, schedule.get("timezone"))
+                )
+            windows = schedule.get("windows")
+            if not isinstance(windows, list) or not windows:
+                raise ConfigError(
+                    "pools[%d]: 'schedule.windows' must be a non-empty list" % idx
+                )
+            for window_idx, window in enumerate(windows):
+                where = "pools[%d]: 'schedule.windows[%d]" % (idx, window_idx)
+                if not isinstance(window, dict):
+                    raise ConfigError("%s' must be a mapping" % where)
+                start = parse_clock(window.get("start"))
+                end = parse_clock(window.get("end"))
+                if start is None or end is None or start == end:
+                    raise ConfigError(
+                        "%s' must have a different 'start' and 'end' as HH:MM"
+                        % where
+                    )
+                days = window.get("days", list(WEEKDAYS))
+                if not isinstance(days, list) or not set(days) <= set(WEEKDAYS):
+                    raise ConfigError(
+                        "%s.days' must be a list of %s" % (where, ", ".join(WEEKDAYS))
+                    )
+                window_min_ready = window.get("min_ready")
+                if not isinstance(window_min_ready, int) or window_min_ready < 0:
+                    raise ConfigError(
+                        "%s.min_ready' must be a non-negative integer" % where
+                    )
+                if max_ready is not None and window_min_ready > max_ready:
+                    raise ConfigError(
+                        "%s.min_ready' must not be over 'max_ready'" % where
+                    )
+
+        name_prefix = runner.get("name_prefix", resource_prefix)
+        if not isinstance(name_prefix, str) or not name_prefix.startswith(
+            resource_prefix
+        ):
+            raise ConfigError(
+                "pools[%d]: 'runner.name_prefix' must start with '%s'"
+                % (idx, resource_prefix)
+            )
+
+        spawn_concurrency = pool.get("spawn_concurrency", 1)
+        if not isinstance(spawn_concurrency, int) or spawn_concurrency < 1:
+            raise ConfigError(
+                "pools[%d]: 'spawn_concurrency' must be a positive integer" % idx
+            )
+
+        group = runner.get("group")
+        if not isinstance(group, (int, str)) or group == "":
+            raise ConfigError(
+                "pools[%d]: 'runner.group' must be a runner group id or name" % idx
+            )
+
+        for key in ("image", "flavor", "network"):
+            if not instance.get(key):
+                raise ConfigError("pools[%d]: missing 'instance.%s'" % (idx, key))
+
+        for key in ("flavor", "network"):
+            if isinstance(instance[key], list) and not all(instance[key]):
+                raise ConfigError(
+                    "pools[%d]: empty entry in 'instance.%s'" % (idx, key)
+                )
+
+        for network in get_networks(pool):
+            if isinstance(network, str):
+                continue
+            if not isinstance(network, dict) or not set(network) <= {
+                "network",
+                "subnet",
+                "fixed_ip",
+                "port",
+            }:
+                raise ConfigError(
+                    "pools[%d]: 'instance.network' entries must be names or "
+                    "mappings with a 'network', 'subnet', 'fixed_ip' or 'port'" % idx
+                )
+            if "port" in network and len(network) > 1:
+                raise ConfigError(
+                    "pools[%d]: a 'port' in 'instance.network' cannot be set "
+                    "along with a 'network', 'subnet' or 'fixed_ip'" % idx
+                )
+            if "port" not in network and not network.get("network"):
+                raise ConfigError(
+                    "pools[%d]: 'instance.network' entry without 'network'" % idx
+                )
+
+        scheduler_hints = instance.get("scheduler_hints", {})
+        if not isinstance(scheduler_hints, dict) or not all(
+            isinstance(key, str) for key in scheduler_hints
+        ):
+            raise ConfigError(
+                "pools[%d]: 'instance.scheduler_hints' must be a mapping" % idx
+            )
+        if "group" in scheduler_hints and "server_group" in instance:
+            raise ConfigError(
+                "pools[%d]: 'instance.scheduler_hints' cannot set 'group' along "
+                "with 'instance.server_group'" % idx
+            )
+
+        tags = instance.get("tags", [])
+        if not isinstance(tags, list) or not all(map(is_valid_tag, tags)):
+            raise ConfigError(
+                "pools[%d]: 'instance.tags' must be a list of strings of at most "
+                "%d characters without ',' or '/'" % (idx, MAX_TAG_LENGTH)
+            )
+
+        if not isinstance(instance.get("config_drive", False), bool):
+            raise ConfigError(
+                "pools[%d]: 'instance.config_drive' must be a boolean" % idx
+            )
+
+        description = instance.get("description", DEFAULT_DESCRIPTION)
+        try:
+            description.format(pool="", labels="", name="")
+        except (AttributeError, IndexError, KeyError, ValueError):
+            raise ConfigError(
+                "pools[%d]: 'instance.description' must be a string which can "
+                "only use {pool}, {labels} and {name}" % idx
+            )
+
+        extra_server_fields = instance.get("extra_server_fields", {})
+        if not isinstance(extra_server_fields, dict) or not all(
+            isinstance(key, str) for key in extra_server_fields
+        ):
+            raise ConfigError(
+                "pools[%d]: 'instance.extra_server_fields' must be a mapping" % idx
+            )
+        reserved = sorted(set(extra_server_fields) & set(RESERVED_SERVER_FIELDS))
+        if reserved:
+            raise ConfigError(
+                "pools[%d]: 'instance.extra_server_fields' cannot set %s"
+                % (idx, ", ".join(reserved))
+            )
+        try:
+            json.dumps(extra_server_fields)
+        except (TypeError, ValueError):
+            raise ConfigError(
+                "pools[%d]: 'instance.extra_server_fields' must only hold JSON "
+                "values" % idx
+            )
+
+        computed_labels = runner.get("computed_labels", [])
+        if not isinstance(computed_labels, list) or not all(
+            isinstance(key, str) and key in COMPUTED_LABELS for key in computed_labels
+        ):
+            raise ConfigError(
+                "pools[%d]: 'runner.computed_labels' must be a list of %s"
+                % (idx, ", ".join(COMPUTED_LABELS))
+            )
+        if (
+            "availability_zone" in computed_labels
+            and "availability_zone" not in extra_server_fields
+        ):
+            raise ConfigError(
+                "pools[%d]: 'runner.computed_labels' needs an 'availability_zone' "
+                "in 'instance.extra_server_fields'" % idx
+            )
+        if (
+            "flavor" in computed_labels
+            and isinstance(instance["flavor"], list)
+            and len(instance["flavor"]) > 1
+        ):
+            raise ConfigError(
+                "pools[%d]: 'runner.computed_labels' can only have a 'flavor' with "
+                "a single 'instance.flavor'" % idx
+            )
+
+        volumes = instance.get("volumes", [])
+        if not isinstance(volumes, list) or not all(
+            isinstance(volume, dict)
+            and isinstance(volume.get("size"), int)
+            and volume["size"] > 0
+            for volume in volumes
+        ):
+            raise ConfigError(
+                "pools[%d]: 'instance.volumes' entries need a positive 'size'" % idx
+            )
+
+        if "key_name" in instance and not instance["key_name"]:
+            raise ConfigError("pools[%d]: 'instance.key_name' is empty" % idx)
+        if "public_key" in instance and "key_name" not in instance:
+            raise ConfigError(
+                "pools[%d]: 'instance.public_key' requires 'instance.key_name'" % idx
+            )
+
+        paths = {"/start.sh", "/etc/profile.d/gha-proxy.sh"}
+        extra_write_files = instance.get("extra_write_files", [])
+        if not isinstance(extra_write_files, list) or not all(
+            isinstance(write_file, dict) for write_file in extra_write_files
+        ):
+            raise ConfigError(
+                "pools[%d]: 'instance.extra_write_files' must be a list of mappings"
+                % idx
+            )
+        for write_file in extra_write_files:
+            if not write_file.get("path"):
+                raise ConfigError(
+                    "pools[%d]: 'instance.extra_write_files' entry without path" % idx
+                )
+            if write_file["path"] in paths:
+                raise ConfigError(
+                    "pools[%d]: duplicate path '%s' in 'instance.extra_write_files'"
+                    % (idx, write_file["path"])
+                )
+            paths.add(write_file["path"])
+
+        if instance.get("gzip_user_data", "auto") not in ("auto", True, False):
+            raise ConfigError(
+                "pools[%d]: 'instance.gzip_user_data' must be a boolean or 'auto'"
+                % idx
+            )
+
+        if "runcmd" in instance and "extra_runcmd" in instance:
+            raise ConfigError(
+                "pools[%d]: only one of 'instance.runcmd' and "
+                "'instance.extra_runcmd' can be set" % idx
+            )
+
+        if "start_script" in instance and "start_script_content" in instance:
+            raise ConfigError(
+                "pools[%d]: only one of 'instance.start_script' and "
+                "'instance.start_script_content' can be set" % idx
+            )
+        if not isinstance(instance.get("start_script_content", ""), str):
+            raise ConfigError(
+                "pools[%d]: 'instance.start_script_content' must be a string" % idx
+            )
+
+        registration = get_registration(get_github_configs(cfg)[github_name])
+        if registration == "token" and not isinstance(runner.get("group"), str):
+            raise ConfigError(
+                "pools[%d]: 'runner.group' must be a name to register runners "
+                "with a registration token" % idx
+            )
+        try:
+            load_start_script(pool, registration)
+        except OSError as e:
+            raise ConfigError("pools[%d]: cannot read start script: %s" % (idx, e))
+        except CloudConfigError as e:
+            raise ConfigError("pools[%d]: %s" % (idx, e))
+
+
+def load_start_script(pool: dict, registration: str = "jit") -> str:
+    """Read the start script of a pool, checking its placeholders.
+
+    Pools can use their own script, either from the file at
+    ``instance.start_script`` or inline in ``instance.start_script_content``,
+    and use scripts/start.sh otherwise.  The script needs the placeholder for
+    the way its runners are registered, and may have the other one.
+    """
+    instance = pool["instance"]
+    if "start_script_content" in instance:
+        source = "'instance.start_script_content'"
+        script = instance["start_script_content"]
+    else:
+        source = instance.get("start_script", START_SCRIPT)
+        with open(source, "r", encoding="utf-8") as f:
+            script = f.read()
+
+    placeholder = JIT_CONFIG_PLACEHOLDER
+    if registration == "token":
+        placeholder = CONFIG_ARGS_PLACEHOLDER
+    if placeholder not in script:
+        raise CloudConfigError("%s has no %s placeholder" % (source, placeholder))
+
+    unknown = set(re.findall(r"___[A-Z0-9_]+___", script)) - {
+        JIT_CONFIG_PLACEHOLDER,
+        CONFIG_ARGS_PLACEHOLDER,
+    }
+    if unknown:
+        raise CloudConfigError(
+            "%s has unknown placeholders: %s" % (source, ", ".join(sorted(unknown)))
+        )
+
+    return script
+
+
+def apply_proxy_config(cfg: dict):
+    """Export the configured proxy settings for all outbound requests.
+
+    Both the GitHub and OpenStack clients use requests, which reads these
+    variables on every request, so settings from the configuration take
+    precedence over the environment the controller was started with.
+    """
+    proxy = cfg.get("proxy") or {}
+    for key in ("http_proxy", "https_proxy", "no_proxy"):
+        if proxy.get(key):
+            os.environ[key] = os.environ[key.upper()] = proxy[key]
+
+
+def connect_openstack(cfg: dict):
+    # NOTE: The timeout applies to every request, including authenticating and
+    #       discovering endpoints, so that a hung Keystone can't wedge the
+    #       maintenance cycles.  A failed authentication isn't cached, so it is
+    #       retried by the next request.
+    region = cfg["openstack"].get("region")
+    cloud = openstack.connect(
+        cloud=cfg["openstack"]["cloud"],
+        region_name=region,
+        api_timeout=cfg["openstack"].get("api_timeout", 60),
+    )
+
+    if region is not None:
+        with openstack_errors():
+            regions = get_catalog_regions(cloud)
+        if region not in regions:
+            raise ConfigError(
+                "region '%s' not found in cloud '%s', expected one of: %s"
+                % (region, cfg["openstack"]["cloud"], ", ".join(sorted(regions)))
+            )
+
+    # NOTE: The service catalog comes with the token, and both are cached by
+    #       the session until the token expires.  Looking up the endpoints
+    #       once here makes sure any missing service fails at startup rather
+    #       than in the middle of a maintenance cycle.
+    for service_type in get_required_services(cfg):
+        with openstack_errors():
+            endpoint = cloud.endpoint_for(service_type)
+        if not endpoint:
+            raise ConfigError(
+                "no %s endpoint found in cloud '%s'"
+                % (service_type, cfg["openstack"]["cloud"])
+            )
+
+    return cloud
+
+
+def get_catalog_regions(cloud) -> set[str]:
+    access = cloud.session.auth.get_access(cloud.session)
+    return {
+        endpoint.get("region_id") or endpoint.get("region")
+        for service in access.service_catalog.catalog
+        for endpoint in service.get("endpoints", [])
+    } - {None}
+
+
+def get_required_services(cfg: dict) -> list[str]:
+    services = ["compute", "network"]
+    if any(not is_uuid(pool["instance"]["image"]) for pool in cfg["pools"]):
+        services.append("image")
+    if any(pool["instance"].get("volumes") for pool in cfg["pools"]):
+        services.append("block-storage")
+    return services
+
+
+def get_github_configs(cfg: dict) -> dict[str, dict]:
+    """Return the GitHub organizations in the configuration, by name.
+
+    The ``github`` section is named "default", and any other organization is
+    listed under ``github_orgs``.  Pools pick one with ``runner.github``.
+    """
+    return {"default": cfg["github"], **(cfg.get("github_orgs") or {})}
+
+
+def get_github_name(pool: dict) -> str:
+    return pool["runner"].get("github", "default")
+
+
+def get_github_config(pool: dict) -> dict:
+    return get_github_configs(CFG)[get_github_name(pool)]
+
+
+def get_github_name_for(github_cfg: dict) -> str | None:
+    """Return the name of a GitHub organization's configuration, if current."""
+    return next(
+        (
+            name
+            for name, other_cfg in get_github_configs(CFG).items()
+            if other_cfg is github_cfg
+        ),
+        None,
+    )
+
+
+def get_github_url(github_cfg: dict) -> str:
+    return github_cfg.get("url", "https://github.com").rstrip("/")
+
+
+def get_github_api_url(github_cfg: dict) -> str:
+    return github_cfg.get("api_url", "https://api.github.com").rstrip("/")
+
+
+def get_registration(github_cfg: dict) -> str:
+    return github_cfg.get("registration", "jit")
+
+
+def get_pools_for_github(name: str, pools: list[dict] | None = None) -> list[dict]:
+    pools = CFG["pools"] if pools is None else pools
+    return [pool for pool in pools if get_github_name(pool) == name]
+
+
+def get_pool_name(pool: dict) -> str:
+    return pool.get("name", pool["runner"]["label"])
+
+
+def get_pool(name: str) -> dict | None:
+    return next((pool for pool in CFG["pools"] if get_pool_name(pool) == name), None)
+
+
+class CappedGithubRetry(GithubRetry):
+    """Retries of PyGithub which give up rather than wait over a maximum."""
+
+    def __init__(self, max_retry_wait: float = 60, **kwargs):
+        super().__init__(**kwargs)
+        self.max_retry_wait = max_retry_wait
+
+    def new(self, **kwargs):
+        return super().new(max_retry_wait=self.max_retry_wait, **kwargs)
+
+    def sleep(self, response=None):
+        delay = (
+            response is not None and self.get_retry_after(response)
+        ) or self.get_backoff_time()
+        if delay > self.max_retry_wait:
+            raise requests.exceptions.RetryError(
+                "GitHub asked to retry in %s seconds, over max_retry_wait=%s"
+                % (delay, self.max_retry_wait)
+            )
+        super().sleep(response)
+
+
+def connect_github(github_cfg: dict) -> github.Github:
+    auth = github.Auth.Token(github_cfg["token"])
+    retry = CappedGithubRetry(
+        max_retry_wait=github_cfg.get("max_retry_wait", 60), total=5
+    )
+    return github.Github(
+        auth=auth, retry=retry, base_url=get_github_api_url(github_cfg)
+    )
+
+
+parser = argparse.ArgumentParser(
+    description="Auto-scaling self-hosted GitHub Actions runners for OpenStack"
+)
+parser.add_argument(
+    "--config",
+    default=os.environ.get("GHA_CONFIG", "config.yml"),
+    help="path to the configuration file (default: config.yml)",
+)
+parser.add_argument(
+    "--dry-run",
+    action="store_true",
+    help="log what would be created or deleted without doing it",
+)
+parser.add_argument(
+    "--preflight",
+    action="store_true",
+    help="check the configuration against GitHub and OpenStack, then exit",
+)
+
+# NOTE: When running under uWSGI, the arguments are the ones of uWSGI itself
+#       so anything unknown is ignored.
+ARGS, _ = parser.parse_known_args()
+
+CONFIG_OVERRIDES = {"dry_run": True} if ARGS.dry_run else None
+CONFIG_MTIME = os.path.getmtime(ARGS.config)
+RELOAD_REQUESTED = threading.Event()
+
+try:
+    CFG = load_config(ARGS.config, CONFIG_OVERRIDES)
+except (ConfigError, OSError, yaml.YAMLError) as e:
+    if not ARGS.preflight:
+        raise
+    print("FAIL configuration: %s" % e)
+    sys.exit(1)
+apply_proxy_config(CFG)
+
+# NOTE: In preflight mode, connecting to OpenStack is one of the checks.
+CLOUD = None if ARGS.preflight else connect_openstack(CFG)
+
+# NOTE: Bounds the number of servers being created or deleted at once across
+#       all pools, webhooks and maintenance cycles, to go easy on the cloud.
+OPENSTACK_SEMAPHORE = threading.BoundedSemaphore(
+    CFG["openstack"].get("concurrency", 8)
+)
+
+# NOTE: In dry-run mode, nothing is created or deleted in GitHub or OpenStack,
+#       but all listing still happens so that the logged decisions are real.
+DRY_RUN = CFG.get("dry_run", False)
+
+GITHUBS = {
+    name: connect_github(github_cfg)
+    for name, github_cfg in get_github_configs(CFG).items()
+}
 
 app = Flask(__name__)
 app.logger.setLevel(logging.INFO)
 
-webhook = Webhook(app, endpoint="/webhook")
+# NOTE: Every server and runner created or deleted is recorded by the audit
+#       logger, which can be sent to its own file with 'audit_log'.
+AUDIT = logging.getLogger("gha.audit")
+AUDIT.setLevel(logging.INFO)
+AUDIT.propagate = False
+
+
+def setup_audit_log(cfg: dict):
+    path = cfg.get("audit_log")
+    handler = default_handler
+    if path:
+        handler = logging.FileHandler(path, encoding="utf-8")
+        handler.setFormatter(logging.Formatter("%(message)s"))
+
+    for old in AUDIT.handlers[:]:
+        AUDIT.removeHandler(old)
+        if old is not default_handler:
+            old.close()
+    AUDIT.addHandler(handler)
+
+
+def audit(action: str, outcome: str, reason: str, **fields):
+    """Record an action in the audit log as a JSON object.
+
+    The outcome is one of "success", "failure", "skipped" or "dry_run", and
+    the reason says what triggered the action.
+    """
+    event = {
+        "time": datetime.now(timezone.utc).isoformat(),
+        "action": action,
+        "outcome": outcome,
+        "reason": reason,
+        **fields,
+    }
+    AUDIT.info(json.dumps(event, default=str))
+
+
+setup_audit_log(CFG)
+
+# NOTE: The webhook secret is only read at startup.
+webhook = Webhook(
+    app, endpoint="/webhook", secret=(CFG.get("webhook") or {}).get("secret")
+)
+
+
+def setup_tracing():
+    """Set up exporting traces over OTLP, if it is configured.
+
+    Exporting is enabled by the standard ``OTEL_EXPORTER_OTLP_*`` environment
+    variables, and requires the ``opentelemetry-sdk`` and
+    ``opentelemetry-exporter-otlp-proto-http`` packages to be installed.
+    """
+    if not (
+        os.environ.get("OTEL_EXPORTER_OTLP_ENDPOINT")
+        or os.environ.get("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+    ):
+        return None
+
+    try:
+        from opentelemetry.exporter.otlp.proto.http.trace_exporter import (
+            OTLPSpanExporter,
+        )
+        from opentelemetry.sdk.resources import Resource
+        from opentelemetry.sdk.trace import TracerProvider
+        from opentelemetry.sdk.trace.export import BatchSpanProcessor
+    except ImportError:
+        app.logger.warning("OpenTelemetry is not installed, not exporting traces")
+        return None
+
+    provider = TracerProvider(
+        resource=Resource.create({"service.name": "github-actions-openstack"})
+    )
+    provider.add_span_processor(BatchSpanProcessor(OTLPSpanExporter()))
+    return provider.get_tracer(__name__)
+
+
+TRACER = setup_tracing()
+
+
+def traced(func):
+    @functools.wraps(func)
+    def wrapper(*args, **kwargs):
+        if TRACER is None:
+            return func(*args, **kwargs)
+        with TRACER.start_as_current_span(func.__name__):
+            return func(*args, **kwargs)
+
+    return wrapper
+
+
+def set_span_attributes(**attributes):
+    if TRACER is None:
+        return
+
+    from opentelemetry import trace
+
+    trace.get_current_span().set_attributes(
+        {key: value for key, value in attributes.items() if value is not None}
+    )
+
+
+# NOTE: Latency histograms of the requests to GitHub and OpenStack by API and
+#       operation, along with the number of failed requests, served by
+#       /metrics.  Buckets are upper bounds in seconds.
+LATENCY_BUCKETS = (0.05, 0.1, 0.25, 0.5, 1, 2.5, 5, 10, 30, 60)
+LATENCIES: dict[tuple[str, str], dict] = {}
+REQUEST_ERRORS: collections.Counter[tuple[str, str]] = collections.Counter()
+METRICS_LOCK = threading.Lock()
+
+
+@contextlib.contextmanager
+def timed(api: str, op: str):
+    start = time.monotonic()
+    try:
+        yield
+    except Exception:
+        with METRICS_LOCK:
+            REQUEST_ERRORS[(api, op)] += 1
+        raise
+    finally:
+        elapsed = time.monotonic() - start
+        with METRICS_LOCK:
+            histogram = LATENCIES.setdefault(
+                (api, op),
+                {"buckets": [0] * len(LATENCY_BUCKETS), "count": 0, "sum": 0.0},
+            )
+            for idx, bound in enumerate(LATENCY_BUCKETS):
+                if elapsed <= bound:
+                    histogram["buckets"][idx] += 1
+            histogram["count"] += 1
+            histogram["sum"] += elapsed
+
+
+# NOTE: Keys of runners which have been seen online at least once, this is
+#       used to tell apart runners which are still booting from the ones which
+#       went offline (e.g. ephemeral runners which already ran their job).
+SEEN_ONLINE: set[tuple[str, str]] = set()
+
+# NOTE: When each runner was first listed, since GitHub doesn't tell when it
+#       was registered, so that runners which just registered aren't deleted.
+FIRST_SEEN: dict[tuple[str, str], str] = {}
+
+# NOTE: Consecutive cycles each runner was listed as offline in without a
+#       healthy server (see `plan`), so that one which only flaps offline isn't
+#       deleted.
+OFFLINE_CYCLES: dict[tuple[str, str], int] = {}
+
+# NOTE: Snapshot of the pools and their capacity as of the last maintenance
+#       cycle, served by /status.
+STATUS: dict = {"last_cycle": None, "pools": {}, "unassigned_instances": {}}
+
+# NOTE: Failed servers kept for debugging as of the last cycle, served by
+#       /status.
+DEBUG_HOLDS: list[dict] = []
+
+# NOTE: Monotonic time of the last scale up and down of each pool, used to
+#       enforce the cooldown between them.
+LAST_SCALED: dict[str, dict[str, float]] = {}
+LAST_SCALED_LOCK = threading.Lock()
+
+# NOTE: Servers created by the controller by id, with their pool, runner name
+#       and creation time.  This is persisted to the state file (if any) so it
+#       can stand in for the live servers when they cannot be listed.
+INSTANCES: dict[str, dict] = {}
+INSTANCES_LOCK = threading.Lock()
+
+# NOTE: Runners registered in GitHub whose server isn't created yet, by name,
+#       with their organization and registration time.  They are persisted
+#       right away along with the instances, so that the runners left behind
+#       by a crash in between can be reaped when the controller starts again.
+REGISTRATIONS: dict[str, dict] = {}
+STATE_FILE_LOCK = threading.Lock()
+
+# NOTE: Total number of failed deletes, and number of consecutive maintenance
+#       cycles with failed deletes, used to report the controller as degraded.
+DELETE_FAILURES = 0
+DELETE_FAILURE_CYCLES = 0
+
+# NOTE: Number of consecutive maintenance cycles which failed to list the
+#       servers, and the monotonic time before which the cloud isn't tried
+#       again once there were too many of them.
+CLOUD_FAILURES = 0
+CLOUD_RETRY_AT: float | None = None
+
+# NOTE: Reason why each degraded pool cannot be scaled up, along with the
+#       monotonic time it was last logged at.
+DEGRADED_POOLS: dict[str, tuple[str, float]] = {}
+
+# NOTE: Last error of each pool which failed to scale up, with its category
+#       and time, until a cycle scales it up without errors.
+POOL_ERRORS: dict[str, dict] = {}
+POOL_ERRORS_LOCK = threading.Lock()
+
+# NOTE: Consecutive cycles each pool ended under its min_ready after failing
+#       to scale up, until a cycle finds it at min_ready.  It is guarded by the
+#       lock of the pool errors.
+STARVED_CYCLES: dict[str, int] = {}
+
+# NOTE: Requests left in the GitHub rate limit of each organization, with
+#       the time it resets at, as of the last response.
+RATE_LIMITS: dict[str, tuple[int, float]] = {}
+RATE_LIMITS_LOCK = threading.Lock()
+
+# NOTE: Runners listed without any labels for each organization, which GitHub
+#       does for some of their states.  It is guarded by the metrics lock.
+UNLABELED_RUNNERS: dict[str, int] = {}
+
+# NOTE: Hash of the servers and runners seen by the last cycle which cleaned
+#       up, and how many cycles skipped it since.  Only the maintenance cycles
+#       use them, which never run concurrently.
+LAST_INVENTORY_HASH: str | None = None
+SKIPPED_CLEAN_UPS = 0
+
+# NOTE: Servers which are only recognized by their name, so that the warning
+#       about them is only logged once.
+LEGACY_SERVERS: set[str] = set()
+
+# NOTE: Pool of each queued job by job id, tracked from the webhooks and used
+#       to scale up pools with a 'max_ready' beyond their min_ready.  Keying
+#       them by id makes replayed deliveries harmless.
+QUEUED_JOBS: dict[int, str] = {}
+QUEUED_JOBS_LOCK = threading.Lock()
+
+# NOTE: When each queued job was queued, so that the ones whose start or
+#       completion was never delivered expire.  It is guarded by the lock of
+#       the queued jobs as well.
+QUEUED_AT: dict[int, float] = {}
+
+# NOTE: Runners of each pool by state as of the last cycle, for webhooks to
+#       reuse its ready runners and keep the pool under its max_ready and
+#       max_concurrent_builds.  Servers created since then count as booting
+#       until the next cycle lists them (see `record_creations`).  It is
+#       guarded by the lock of the queued jobs, which are counted against it.
+RUNNER_STATES: dict[str, dict[str, int]] = {}
+
+# NOTE: Servers being created for each pool, by webhooks and maintenance cycles
+#       alike, so that together they don't go over its max_ready or its
+#       max_concurrent_builds, or over max_total_instances.
+PENDING_CREATIONS: collections.Counter[str] = collections.Counter()
+PENDING_CREATIONS_LOCK = threading.Lock()
+
+# NOTE: Servers listed by the last cycle, and created since, which count
+#       against max_total_instances along with the ones being created.  It is
+#       guarded by the lock of the pending creations.
+SERVER_COUNT = 0
+
+# NOTE: Recently processed webhook deliveries, by id, with the monotonic time
+#       they were received at.  GitHub redelivers webhooks, so deliveries seen
+#       before are ignored.
+DELIVERIES: collections.OrderedDict[str, float] = collections.OrderedDict()
+DELIVERIES_LOCK = threading.Lock()
+
+# NOTE: Webhook events which are acted upon, any other is acknowledged and
+#       ignored.
+WEBHOOK_EVENTS = ("workflow_job",)
+
+# NOTE: Current weight of each pool for the weighted round-robin between pools
+#       which share a label, as done by nginx for its upstreams.
+POOL_WEIGHTS: dict[str, int] = {}
+POOL_WEIGHTS_LOCK = threading.Lock()
+
+scheduler = APScheduler()
+scheduler.init_app(app)
+
+
+@app.before_request
+def set_delivery_id():
+    # NOTE: GitHub sends a unique id with every delivery, which is used to
+    #       correlate a webhook with the actions it triggered.
+    if request.path != "/webhook":
+        return
+
+    g.delivery_id = request.headers.get("X-GitHub-Delivery") or str(uuid.uuid4())
+
+    event = request.headers.get("X-GitHub-Event")
+    if event not in WEBHOOK_EVENTS:
+        app.logger.info(
+            "Ignoring unsupported %s event (delivery %s)", event, g.delivery_id
+        )
+        return jsonify(action="ignored", reason="unsupported event"), 200
+
+    # NOTE: The payload is decoded here so that a malformed one gets a clear
+    #       answer, rather than the error page of the webhook library.
+    if not isinstance(get_webhook_payload(), dict):
+        app.logger.warning("Malformed webhook payload (delivery %s)", g.delivery_id)
+        return jsonify(action="ignored", reason="malformed payload"), 400
+
+    if is_duplicate_delivery(g.delivery_id):
+        app.logger.info("Ignoring duplicate delivery %s", g.delivery_id)
+        return jsonify(action="ignored", reason="duplicate delivery"), 200
+
+
+def get_webhook_payload():
+    """Decode the payload of a webhook like the webhook library does.
+
+    GitHub sends it either as JSON or as a ``payload`` form field, None is
+    returned if it cannot be decoded.
+    """
+    if request.mimetype == "application/x-www-form-urlencoded":
+        try:
+            return json.loads(request.form["payload"])
+        except (KeyError, ValueError):
+            return None
+    return request.get_json(silent=True)
+
+
+def is_duplicate_delivery(delivery_id: str) -> bool:
+    """Check if a delivery was seen recently, remembering it if it was not.
+
+    Up to ``webhook.delivery_cache_size`` deliveries are remembered, for up to
+    ``webhook.delivery_cache_ttl`` seconds, unless handling them fails.
+    """
+    webhook_cfg = CFG.get("webhook") or {}
+    size = webhook_cfg.get("delivery_cache_size", 1000)
+    ttl = webhook_cfg.get("delivery_cache_ttl", 3600)
+
+    now = time.monotonic()
+    with DELIVERIES_LOCK:
+        while DELIVERIES and (
+            len(DELIVERIES) >= size or now - next(iter(DELIVERIES.values())) > ttl
+        ):
+            DELIVERIES.popitem(last=False)
+
+        if delivery_id in DELIVERIES:
+            return True
+
+        DELIVERIES[delivery_id] = now
+        return False
+
+
+@app.after_request
+def add_delivery_id(response):
+    # NOTE: The webhook library always answers with an empty body, so what the
+    #       handler did replaces it to show up in GitHub's delivery log.
+    if "webhook_result" in g:
+        body, status = g.webhook_result
+        response = jsonify(body)
+        response.status_code = status
+    if "delivery_id" in g:
+        response.headers["X-GitHub-Delivery"] = g.delivery_id
+        # NOTE: A delivery which failed is forgotten, so that it isn't taken
+        #       for a duplicate when GitHub delivers it again.
+        if response.status_code >= 500:
+            with DELIVERIES_LOCK:
+                DELIVERIES.pop(g.delivery_id, None)
+    return response
+
+
+def set_webhook_result(action: str, status: int = 200, **fields):
+    g.webhook_result = ({"action": action, **fields}, status)
+
+
+@webhook.hook(event_type="workflow_job")
+@traced
+def on_workflow_job(data):
+    set_span_attributes(action=data.get("action"), delivery=g.delivery_id)
+    app.logger.info(
+        "Received workflow job %s (delivery %s)", data.get("action"), g.delivery_id
+    )
+
+    try:
+        org = data["organization"]["login"]
+        labels = {normalize_label(label) for label in data["workflow_job"]["labels"]}
+        job_id = data["workflow_job"]["id"]
+        runner_name = data["workflow_job"].get("runner_name")
+    except (AttributeError, KeyError, TypeError):
+        app.logger.warning("Malformed workflow job (delivery %s)", g.delivery_id)
+        set_webhook_result("ignored", 400, reason="malformed workflow job")
+        return
+
+    github_names = [
+        name
+        for name, github_cfg in get_github_configs(CFG).items()
+        if github_cfg["org"] == org
+    ]
+    if not github_names:
+        set_webhook_result("ignored", reason="unknown organization")
+        return
+
+    if not is_allowed_by_label_policy(CFG, labels):
+        set_webhook_result("ignored", reason="labels not allowed")
+        return
+
+    if data["action"] in ("in_progress", "completed"):
+        with QUEUED_JOBS_LOCK:
+            QUEUED_JOBS.pop(job_id, None)
+            QUEUED_AT.pop(job_id, None)
+
+    if data["action"] == "queued":
+        pools = [
+            pool
+            for pool in CFG["pools"]
+            if get_github_name(pool) in github_names
+            and normalize_label(pool["runner"]["label"]) in labels
+        ]
+        if not pools:
+            set_webhook_result("ignored", reason="unmatched labels")
+            return
+
+        with QUEUED_JOBS_LOCK:
+            replayed = job_id in QUEUED_JOBS
+            if not replayed:
+                idle_pools = get_idle_pools(pools)
+                pool = select_pool(idle_pools or pools)
+                QUEUED_JOBS[job_id] = get_pool_name(pool)
+                QUEUED_AT[job_id] = time.monotonic()
+                states = dict(RUNNER_STATES.get(get_pool_name(pool), {}))
+        if replayed:
+            app.logger.info("Job %s is already queued, ignoring it", job_id)
+            set_webhook_result("ignored", reason="job already queued")
+            return
+        if idle_pools:
+            app.logger.info(
+                "Job %s can run on an idle runner of %s, not scaling up",
+                job_id,
+                get_pool_name(pool),
+            )
+            set_webhook_result(
+                "ignored", reason="idle runner available", pool=get_pool_name(pool)
+            )
+            return
+
+        with pending_creations(pool, 1, states) as reserved:
+            if not reserved:
+                app.logger.info(
+                    "Job %s would take %s over its max_ready, its "
+                    "max_concurrent_builds or max_total_instances, not scaling up",
+                    job_id,
+                    get_pool_name(pool),
+                )
+                set_webhook_result(
+                    "ignored", reason="pool at capacity", pool=get_pool_name(pool)
+                )
+                return
+            # NOTE: A pool which can't scale up for now is left to the next
+            #       maintenance cycle, rather than failing the delivery for
+            #       GitHub to deliver it again.
+            try:
+                scale_up(pool, "job_queued")
+            except (ConfigError, CloudConfigError) as e:
+                app.logger.warning("Not scaling up %s: %s", get_pool_name(pool), e)
+                set_webhook_result("skipped", reason=str(e), pool=get_pool_name(pool))
+                return
+            except OpenStackError as e:
+                if e.category != "unavailable":
+                    raise
+                app.logger.warning("Not scaling up %s: %s", get_pool_name(pool), e)
+                set_webhook_result("failed", reason=str(e), pool=get_pool_name(pool))
+                return
+            record_creations(pool, 1)
+        record_scale(pool, "up")
+        notify("scale_up", pool=get_pool_name(pool), count=1)
+        set_webhook_result("scaled_up", pool=get_pool_name(pool))
+        return
+
+    if data["action"] == "completed":
+        # NOTE: Servers are matched like runners are when correlating them,
+        #       by the organization and runner name kept in their metadata,
+        #       since their own name may differ from the runner's.
+        server = None
+        if runner_name:
+            keys = {(name, runner_name) for name in github_names}
+            with openstack_errors():
+                servers = list_servers()
+            server = next((s for s in servers if server_key(s) in keys), None)
+        if server is None:
+            set_webhook_result("ignored", reason="unmanaged runner")
+            return
+        if not delete_node(server, reason="job_completed"):
+            set_webhook_result("ignored", reason="server not deleted")
+            return
+
+        notify("scale_down", count=1)
+        set_webhook_result("scaled_down", server=server.name)
+        return
+
+    if data["action"] == "in_progress":
+        set_webhook_result("dequeued", job=job_id)
+        return
+
+    set_webhook_result("ignored", reason="unsupported action")
+
+
+@contextlib.contextmanager
+def pending_creations(pool: dict, wanted: int, states: dict[str, int]):
+    """Reserve up to ``wanted`` server creations for a pool while they run.
+
+    With a ``max_ready`` or ``max_concurrent_builds``, the creations in flight
+    count against them on top of the pool's runners, given by state, so that
+    webhooks and maintenance cycles creating servers at once don't go over
+    them together.  The creations of all pools count against
+    ``max_total_instances`` the same way, on top of the servers.  How many
+    creations were reserved is yielded, which may be none.
+    """
+    name = get_pool_name(pool)
+    ready = states.get("idle", 0) + states.get("booting", 0)
+    limits = []
+    if pool.get("max_ready") is not None:
+        limits.append(pool["max_ready"] - ready)
+    if pool.get("max_concurrent_builds") is not None:
+        limits.append(pool["max_concurrent_builds"] - ready - states.get("busy", 0))
+    with PENDING_CREATIONS_LOCK:
+        pending = PENDING_CREATIONS[name]
+        limits = [limit - pending for limit in limits]
+        if CFG.get("max_total_instances") is not None:
+            limits.append(
+                CFG["max_total_instances"]
+                - SERVER_COUNT
+                - sum(PENDING_CREATIONS.values())
+            )
+        reserved = max(min([wanted] + limits), 0)
+        PENDING_CREATIONS[name] += reserved
+    try:
+        yield reserved
+    finally:
+        with PENDING_CREATIONS_LOCK:
+            PENDING_CREATIONS[name] -= reserved
+
+
+def get_runners_needed(states: dict[str, int], jobs: int) -> int:
+    """Return how many new runners a pool needs to run ``jobs`` queued jobs.
+
+    Its ready (idle or booting) runners, given by state, pick up queued jobs
+    before any new one would, so they are reused and only the jobs beyond
+    them need a new runner.  Both maintenance cycles and webhooks go through
+    this before reserving creations and so before generating a JIT config, so
+    that no registration is created which wouldn't be used.
+    """
+    return max(jobs - states.get("idle", 0) - states.get("booting", 0), 0)
+
+
+def record_creations(pool: dict, count: int):
+    """Count servers just created for a pool as booting until the next cycle.
+
+    Their reservation is released once they are created, so this keeps them
+    counting against the pool's limits until a cycle lists their runners, and
+    against ``max_total_instances`` until it lists them.
+    """
+    global SERVER_COUNT
+
+    with PENDING_CREATIONS_LOCK:
+        SERVER_COUNT += count
+    with QUEUED_JOBS_LOCK:
+        states = RUNNER_STATES.setdefault(
+            get_pool_name(pool), dict.fromkeys(("idle", "busy", "booting"), 0)
+        )
+        states["booting"] = states.get("booting", 0) + count
+
+
+def set_server_count(count: int):
+    global SERVER_COUNT
+
+    with PENDING_CREATIONS_LOCK:
+        SERVER_COUNT = count
+
+
+def get_idle_pools(pools: list[dict]) -> list[dict]:
+    """Return the pools whose ready runners can run one more queued job.
+
+    Ready runners are counted from the last cycle's inventory rather than
+    listed again, and the caller must hold ``QUEUED_JOBS_LOCK``.
+    """
+    queued = collections.Counter(QUEUED_JOBS.values())
+    return [
+        pool
+        for pool in pools
+        if not get_runners_needed(
+            RUNNER_STATES.get(get_pool_name(pool), {}), queued[get_pool_name(pool)] + 1
+        )
+    ]
+
+
+def select_pool(pools: list[dict]) -> dict:
+    """Pick one of the pools which can run a job, by weighted round-robin.
+
+    Each pool is picked in proportion to its ``weight``, spreading the picks
+    evenly rather than in bursts.
+    """
+    with POOL_WEIGHTS_LOCK:
+        for pool in pools:
+            POOL_WEIGHTS[get_pool_name(pool)] = POOL_WEIGHTS.get(
+                get_pool_name(pool), 0
+            ) + pool.get("weight", 1)
+        selected = max(pools, key=lambda pool: POOL_WEIGHTS[get_pool_name(pool)])
+        POOL_WEIGHTS[get_pool_name(selected)] -= sum(
+            pool.get("weight", 1) for pool in pools
+        )
+    return selected
+
+
+def get_initial_delay(cfg: dict, rng: random.Random = random) -> float:
+    """Pick how long to wait before the first maintenance cycle.
+
+    The delay is up to ``jitter_secs``, so that replicas which start together
+    don't all hit the APIs at the same time.
+    """
+    return rng.uniform(0, cfg.get("jitter_secs", 0))
+
+
+# NOTE: The scheduler delays every run by up to 'jitter_secs', which is only
+#       read at startup.
+@scheduler.task(
+    "interval",
+    id="maintain_min_ready",
+    seconds=30,
+    jitter=CFG.get("jitter_secs", 0) or None,
+    max_instances=1,
+    next_run_time=datetime.now() + timedelta(seconds=get_initial_delay(CFG)),
+)
+@traced
+def maintain_min_ready():
+    maybe_reload_config()
+
+    if not is_cloud_available():
+        app.logger.info("Skipping this cycle, the cloud is unavailable")
+        STATUS["cloud"] = "unavailable"
+        return
+
+    # NOTE: Webhooks still scale up while GitHub is throttled, the routine
+    #       listing of runners of the organization waits for the rate limit to
+    #       reset instead.  Without its runners, its pools are neither scaled
+    #       up nor cleaned up.
+    unlisted = {name for name in get_github_configs(CFG) if is_throttled(name)}
+    if unlisted:
+        app.logger.warning(
+            "Not listing the runners of %s, the GitHub rate limit is low",
+            ", ".join(sorted(unlisted)),
+        )
+
+    # NOTE: Both lists are fetched once per cycle, before scaling up, so that
+    #       servers created during this cycle are never seen without their
+    #       runners by the clean-up below.
+    with INSTANCES_LOCK:
+        known = set(INSTANCES)
+    inventory_complete = True
+    try:
+        with openstack_errors():
+            servers = list_servers()
+        record_cloud_result(None)
+    except OpenStackError as e:
+        record_cloud_result(e)
+        if not CFG.get("state_file"):
+            raise
+
+        app.logger.warning(
+            "Failed to list servers, using the %s persisted ones: %s", len(known), e
+        )
+        servers = get_persisted_servers()
+        inventory_complete = False
+
+    # NOTE: Runners aren't persisted, so an organization whose runners can't
+    #       be listed is handled like a throttled one, rather than guessing
+    #       with an outdated view of them.
+    runners = []
+    for name in get_github_configs(CFG):
+        if name in unlisted:
+            continue
+        try:
+            runners.extend(list_runners(name))
+        except GitHubError as e:
+            app.logger.error("Failed to list the runners of %s: %s", name, e)
+            notify("cycle_error", github=name, error=str(e))
+            unlisted.add(name)
+    SEEN_ONLINE.update(
+        runner_key(runner) for runner in runners if runner.status == "online"
+    )
+    first_seen = datetime.now(timezone.utc).isoformat()
+    for runner in runners:
+        key = runner_key(runner)
+        FIRST_SEEN.setdefault(key, first_seen)
+
+    snapshot = take_snapshot(servers, runners)
+    cycle = plan(CFG, snapshot)
+    for key in list(OFFLINE_CYCLES):
+        if key[0] not in unlisted:
+            del OFFLINE_CYCLES[key]
+    OFFLINE_CYCLES.update(cycle.offline_cycles)
+    unlisted_pools = {
+        get_pool_name(pool)
+        for pool in CFG["pools"]
+        if get_github_name(pool) in unlisted
+    }
+    for name in unlisted_pools:
+        cycle.creates[name] = cycle.wanted[name] = 0
+    with QUEUED_JOBS_LOCK:
+        for name, states in cycle.runners.items():
+            if name not in unlisted_pools:
+                RUNNER_STATES[name] = dict(states)
+    set_server_count(len(snapshot.servers))
+    failed = scale_up_pools(cycle, snapshot)
+
+    app.logger.info(
+        "Maintained %s pools, %s failed%s",
+        len(CFG["pools"]),
+        len(failed),
+        ": " + ", ".join(sorted(failed)) if failed else "",
+    )
+    if failed:
+        notify("cycle_error", pools=sorted(failed))
+
+    hold_for_debugging(cycle, snapshot.now)
+    # NOTE: The servers of an organization whose runners weren't listed would
+    #       look orphaned, so nothing of it is deleted, while the others are
+    #       still cleaned up.
+    if unlisted:
+        app.logger.warning(
+            "Not cleaning up the runners and servers of %s",
+            ", ".join(sorted(unlisted)),
+        )
+        cycle.deletes = [
+            deletion
+            for deletion in cycle.deletes
+            if get_deletion_github(deletion) not in unlisted
+        ]
+    if not inventory_complete:
+        app.logger.warning("Not cleaning up, the inventory is incomplete")
+        record_delete_failures(0)
+    elif not needs_clean_up(servers, runners):
+        app.logger.info("Not cleaning up, nothing changed since the last cycle")
+    else:
+        record_delete_failures(clean_up(cycle, servers))
+
+    update_status(servers, runners)
+
+    # Forget about runners which are gone from GitHub
+    listed = {runner_key(runner) for runner in runners}
+    listed.update(
+        key
+        for key in SEEN_ONLINE | FIRST_SEEN.keys() | OFFLINE_CYCLES.keys()
+        if key[0] in unlisted
+    )
+    SEEN_ONLINE.intersection_update(listed)
+    for key in FIRST_SEEN.keys() - listed:
+        del FIRST_SEEN[key]
+    for key in OFFLINE_CYCLES.keys() - listed:
+        del OFFLINE_CYCLES[key]
+
+    if inventory_complete:
+        reconcile_instances(known, servers, runners)
+    save_state()
+
+
+@dataclasses.dataclass
+class Snapshot:
+    """The state a maintenance cycle is planned from."""
+
+    runners: list
+    servers: list
+    seen_online: set[tuple[str, str]]
+    first_seen: dict[tuple[str, str], str]
+    offline_cycles: dict[tuple[str, str], int]
+    queued_jobs: dict[str, int]
+    last_scaled: dict[str, dict[str, float]]
+    instances: dict[str, dict]
+    registrations: dict[str, dict]
+    now: datetime
+    monotonic: float
+
+
+@dataclasses.dataclass
+class Deletion:
+    reason: str
+    server: object = None
+    runner: object = None
+    pool: dict | None = None
+
+
+def get_deletion_github(deletion: Deletion) -> str:
+    """Return the name of the organization of a deletion's runner or server."""
+    if deletion.runner is not None:
+        return deletion.runner.github_name
+    return server_key(deletion.server)[0]
+
+
+@dataclasses.dataclass
+class Plan:
+    """What a maintenance cycle should do, as decided by plan().
+
+    ``creates`` is the number of nodes to create by pool name, and
+    ``wanted`` the same before max_total_instances is applied.  Deletions
+    which are held back by the cooldown of their pool are kept apart in
+    ``held_deletes``, and so are the names of pools held back from scaling up.
+    Deletions of idle runners which queued jobs of their pool can still pick
+    up are kept apart in ``protected_deletes``, and deletions of failed
+    servers kept for debugging in ``debug_holds``.  ``replacing`` counts the
+    online runners of each pool whose server is gone, and ``min_ready`` is the
+    one of each pool at the time of the cycle.  ``offline_cycles`` is the
+    number of cycles each offline runner has been counted in so far.
+    """
+
+    runners: dict[str, dict[str, int]] = dataclasses.field(default_factory=dict)
+    warm: dict[str, int] = dataclasses.field(default_factory=dict)
+    min_ready: dict[str, int] = dataclasses.field(default_factory=dict)
+    replacing: collections.Counter[str] = dataclasses.field(
+        default_factory=collections.Counter
+    )
+    wanted: dict[str, int] = dataclasses.field(default_factory=dict)
+    creates: dict[str, int] = dataclasses.field(default_factory=dict)
+    deletes: list[Deletion] = dataclasses.field(default_factory=list)
+    held_pools: list[str] = dataclasses.field(default_factory=list)
+    held_deletes: list[Deletion] = dataclasses.field(default_factory=list)
+    protected_deletes: list[Deletion] = dataclasses.field(default_factory=list)
+    offline_cycles: dict[tuple[str, str], int] = dataclasses.field(
+        default_factory=dict
+    )
+    debug_holds: list[Deletion] = dataclasses.field(default_factory=list)
+
+
+def expire_queued_jobs():
+    """Forget the jobs queued for longer than ``queued_job_ttl_secs``.
+
+    Their start or completion may never be delivered, and they would count
+    towards the queue of their pool forever otherwise.  The caller must hold
+    ``QUEUED_JOBS_LOCK``.
+    """
+    ttl = CFG.get("queued_job_ttl_secs", 3600)
+    expired = [
+        job_id
+        for job_id, queued_at in QUEUED_AT.items()
+        if time.monotonic() - queued_at > ttl
+    ]
+    for job_id in expired:
+        app.logger.info("Job %s is queued for too long, forgetting it", job_id)
+        QUEUED_JOBS.pop(job_id, None)
+        del QUEUED_AT[job_id]
+
+
+def take_snapshot(servers: list, runners: list) -> Snapshot:
+    with QUEUED_JOBS_LOCK:
+        expire_queued_jobs()
+        queued_jobs = collections.Counter(QUEUED_JOBS.values())
+    with LAST_SCALED_LOCK:
+        last_scaled = {label: dict(last) for label, last in LAST_SCALED.items()}
+    with INSTANCES_LOCK:
+        instances = {server_id: dict(i) for server_id, i in INSTANCES.items()}
+        registrations = {name: dict(r) for name, r in REGISTRATIONS.items()}
+    return Snapshot(
+        runners=runners,
+        servers=servers,
+        seen_online=set(SEEN_ONLINE),
+        first_seen=dict(FIRST_SEEN),
+        offline_cycles=dict(OFFLINE_CYCLES),
+        queued_jobs=dict(queued_jobs),
+        last_scaled=last_scaled,
+        instances=instances,
+        registrations=registrations,
+        now=datetime.now(timezone.utc),
+        monotonic=time.monotonic(),
+    )
+
+
+def plan(cfg: dict, snapshot: Snapshot) -> Plan:
+    """Decide which nodes a maintenance cycle creates and deletes.
+
+    This has no side effects and only looks at the configuration and the
+    snapshot, the plan is then applied by scale_up_pools() and clean_up().
+    The images, flavors and compute quota of the pools are only checked when
+    the plan is applied, since they take calls to OpenStack.
+    """
+    result = Plan()
+
+    # NOTE: Servers which are building or running a runner which isn't busy
+    #       are warm, whether or not their runner is registered yet.
+    busy = {runner_key(runner) for runner in snapshot.runners if runner.busy}
+    warm = collections.Counter(
+        get_server_pool(server, snapshot.instances)
+        for server in snapshot.servers
+        if server.status in ("BUILD", "ACTIVE") and server_key(server) not in busy
+    )
+
+    pairs = correlate(snapshot.runners, snapshot.servers)
+    for pool in cfg["pools"]:
+        result.runners[get_pool_name(pool)] = dict.fromkeys(
+            ("idle", "busy", "booting", "offline"), 0
+        )
+    for runner, server in pairs:
+        if runner is None:
+            continue
+
+        pool = get_pool_for_runner(runner, cfg["pools"], server)
+        if pool is None:
+            continue
+
+        # NOTE: Runners without a server are deleted below, so they don't
+        #       count towards the pool and get replaced right away.
+        if server is None:
+            if runner.status == "online":
+                result.replacing[get_pool_name(pool)] += 1
+            continue
+
+        state = classify_runner(runner, snapshot.seen_online)
+        result.runners[get_pool_name(pool)][state] += 1
+
+    # NOTE: Runners registered with a registration token only show up once
+    #       their server has booted, until then their server counts as a
+    #       booting runner rather than being deleted as an orphan.
+    registering = {
+        server.id
+        for runner, server in pairs
+        if runner is None and is_registering(cfg, server, snapshot.now)
+    }
+    for server in snapshot.servers:
+        name = get_server_pool(server, snapshot.instances)
+        if server.id in registering and name in result.runners:
+            result.runners[name]["booting"] += 1
+
+    for pool in cfg["pools"]:
+        name = get_pool_name(pool)
+        result.warm[name] = warm[name]
+
+        result.min_ready[name] = get_min_ready(pool, snapshot.now)
+        nodes_to_create = get_nodes_to_create(
+            pool,
+            result.runners[name],
+            snapshot.queued_jobs.get(name, 0),
+            warm[name],
+            result.min_ready[name],
+        )
+        if nodes_to_create > 0 and in_cooldown(cfg, snapshot, pool, "up"):
+            result.held_pools.append(name)
+            nodes_to_create = 0
+        result.wanted[name] = nodes_to_create
+
+    result.creates = result.wanted
+    max_total_instances = cfg.get("max_total_instances")
+    if max_total_instances is not None:
+        budget = max(max_total_instances - len(snapshot.servers), 0)
+        result.creates = distribute_budget(result.wanted, budget)
+
+    # Clean-up servers that don't have runners linked to them anymore
+    for runner, server in pairs:
+        if runner is None and server.id not in registering:
+            result.deletes.append(Deletion("orphaned_server", server=server))
+
+    # NOTE: Runners which are offline only count as such while their server
+    #       is missing or unhealthy, or once they were seen online, so that the
+    #       cycles spent booting on a healthy server don't count towards their
+    #       offline_cycles.
+    for runner, server in pairs:
+        if runner is None or runner.status == "online":
+            continue
+        if (
+            server is not None
+            and server.status in ("ACTIVE", "BUILD")
+            and classify_runner(runner, snapshot.seen_online) == "booting"
+        ):
+            continue
+        key = runner_key(runner)
+        result.offline_cycles[key] = snapshot.offline_cycles.get(key, 0) + 1
+
+    # NOTE: Idle runners are left alone for the queued jobs of their pool,
+    #       which they may be about to be assigned.
+    protected = collections.Counter(snapshot.queued_jobs)
+
+    # Clean-up runners that don't have servers linked to them anymore, or which
+    # have already ran their only job
+    for runner, server in pairs:
+        if runner is None or is_new_runner(cfg, runner, snapshot):
+            continue
+
+        if server is None:
+            if is_being_created(cfg, runner, snapshot):
+                continue
+            reason = "orphaned_runner"
+            if runner.status == "online":
+                reason = "missing_server"
+            elif is_flapping(cfg, runner, result):
+                continue
+            result.deletes.append(Deletion(reason, runner=runner))
+            continue
+
+        pool = get_pool_for_runner(runner, cfg["pools"], server)
+        if pool is None or not should_delete_runner(runner, server, pool, snapshot):
+            continue
+
+        reason = "runner_offline"
+        if is_expired(server, pool, snapshot.now):
+            reason = "max_age"
+        elif is_flapping(cfg, runner, result):
+            continue
+        deletion = Deletion(reason, server, runner, pool)
+        if (
+            protected[get_pool_name(pool)] > 0
+            and classify_runner(runner, snapshot.seen_online) == "idle"
+        ):
+            protected[get_pool_name(pool)] -= 1
+            result.protected_deletes.append(deletion)
+        elif in_cooldown(cfg, snapshot, pool, "down"):
+            result.held_deletes.append(deletion)
+        else:
+            result.deletes.append(deletion)
+
+    for deletion in list(result.deletes):
+        if deletion.server is not None and is_kept_for_debugging(
+            cfg, deletion.server, snapshot.now
+        ):
+            result.deletes.remove(deletion)
+            result.debug_holds.append(deletion)
+
+    return result
+
+
+def is_kept_for_debugging(cfg: dict, server, now: datetime | None = None) -> bool:
+    """Check if a failed server is kept around to be debugged.
+
+    Servers in ERROR or stuck building are kept until they are older than
+    ``keep_failed_instances_secs``, and then deleted like any other.
+    """
+    if server.status not in ("ERROR", "BUILD"):
+        return False
+    age = get_server_age(server, now)
+    return age is not None and age < cfg.get("keep_failed_instances_secs", 0)
+
+
+def hold_for_debugging(cycle: Plan, now: datetime):
+    """Tag the failed servers kept for debugging, and list them in /status."""
+    global DEBUG_HOLDS
+
+    held = {hold["server_id"] for hold in DEBUG_HOLDS}
+    holds = []
+    ttl = CFG.get("keep_failed_instances_secs", 0)
+    for deletion in cycle.debug_holds:
+        server = deletion.server
+        holds.append(
+            {
+                "server": server.name,
+                "server_id": server.id,
+                "status": server.status,
+                "reason": deletion.reason,
+                "until": (
+                    now + timedelta(seconds=ttl - get_server_age(server, now))
+                ).isoformat(),
+            }
+        )
+        tags = getattr(server, "tags", None) or []
+        if server.id in held or DEBUG_HOLD_TAG in tags or DRY_RUN:
+            continue
+
+        app.logger.warning(
+            "Keeping failed server %s (%s) for debugging", server.name, server.status
+        )
+        try:
+            add_server_tag(server, DEBUG_HOLD_TAG)
+        except OpenStackError as e:
+            app.logger.error("Failed to tag server %s: %s", server.name, e)
+    DEBUG_HOLDS = holds
+
+
+def correlate(runners: list, servers: list) -> list[tuple[object, object]]:
+    """Pair up runners with their servers, as ``(runner, server)`` tuples.
+
+    They are matched by organization and runner name, which servers keep in
+    their ``gha-runner-name`` metadata rather than relying on their own name.
+    Runners without a server are paired with None, followed by the servers
+    without a runner.
+    """
+    servers_by_key = {server_key(server): server for server in servers}
+    runner_keys = {runner_key(runner) for runner in runners}
+    pairs = [(runner, servers_by_key.get(runner_key(runner))) for runner in runners]
+    orphans = [server for server in servers if server_key(server) not in runner_keys]
+    return pairs + [(None, server) for server in orphans]
+
+
+def is_new_runner(cfg: dict, runner, snapshot: Snapshot) -> bool:
+    """Check if a runner was first seen less than ``runner_min_age_secs`` ago.
+
+    Such runners are never deleted, since their server may not have been
+    listed yet or may still be settling right after booting.
+    """
+    age = get_age(snapshot.first_seen.get(runner_key(runner)), snapshot.now)
+    return age is not None and age < cfg.get("runner_min_age_secs", 0)
+
+
+def is_flapping(cfg: dict, runner, cycle: Plan) -> bool:
+    """Check if an offline runner may only be flapping.
+
+    That is until it was counted as offline in ``offline_cycles`` cycles in a
+    row, so that a single inconsistent listing doesn't get it deleted.
+    """
+    threshold = cfg.get("offline_cycles", 1)
+    cycles = cycle.offline_cycles.get(runner_key(runner), 0)
+    return threshold > 1 and cycles < threshold
+
+
+def is_being_created(cfg: dict, runner, snapshot: Snapshot) -> bool:
+    """Check if the server of a runner may still be on its way.
+
+    Runners are registered before their server is created, so a runner is
+    left alone for up to ``registration_timeout_secs`` after its registration
+    while its server has not been created.
+    """
+    registration = snapshot.registrations.get(runner.name)
+    if registration is None or registration["github"] != runner.github_name:
+        return False
+    age = get_age(registration["created_at"], snapshot.now)
+    return age is not None and age < cfg.get("registration_timeout_secs", 600)
+
+
+def is_registering(cfg: dict, server, now: datetime | None = None) -> bool:
+    """Check if a server may still be registering its runner by itself.
+
+    This is the case of servers registering their runner with a registration
+    token, for up to ``registration_timeout_secs`` after they were created.
+    """
+    github_cfg = get_github_configs(cfg).get(server_key(server)[0]) or {}
+    if get_registration(github_cfg) != "token":
+        return False
+    age = get_server_age(server, now)
+    return age is not None and age < cfg.get("registration_timeout_secs", 600)
+
+
+def scale_up_pools(cycle: Plan, snapshot: Snapshot) -> list[str]:
+    """Create the nodes of a plan, returning the names of the failed pools."""
+    failed = []
+    nodes_to_create = {}
+    allocated = {}
+    for pool in CFG["pools"]:
+        name = get_pool_name(pool)
+        states = cycle.runners[name]
+        app.logger.info(
+            "%s: Found %s runners, %s idle, %s busy, %s booting, %s offline, "
+            "%s queued jobs, %s warm instances, min_ready=%s",
+            name,
+            sum(states.values()),
+            states["idle"],
+            states["busy"],
+            states["booting"],
+            states["offline"],
+            snapshot.queued_jobs.get(name, 0),
+            cycle.warm[name],
+            cycle.min_ready[name],
+        )
+        if cycle.replacing[name]:
+            app.logger.warning(
+                "%s: Replacing %s online runners whose server is gone",
+                name,
+                cycle.replacing[name],
+            )
+        if name in cycle.held_pools:
+            app.logger.info("%s: Not scaling up, pool recently scaled down", name)
+
+        if cycle.creates[name] <= 0:
+            continue
+
+        try:
+            nodes_to_create[name] = limit_nodes_to_create(
+                pool, cycle.creates[name], allocated
+            )
+        except Exception as e:
+            app.logger.exception("%s: Failed to maintain pool", name)
+            failed.append(name)
+            record_pool_error(name, e)
+
+    if cycle.creates != cycle.wanted:
+        app.logger.warning(
+            "Limited to %s new nodes by max_total_instances=%s",
+            sum(cycle.creates.values()),
+            CFG.get("max_total_instances"),
+        )
+
+    # NOTE: Pools are scaled up concurrently so that a slow or failing pool
+    #       doesn't hold up the others.
+    with ThreadPoolExecutor(max_workers=CFG.get("pool_concurrency", 4)) as executor:
+        future_to_pool = {
+            executor.submit(
+                scale_up_pool, pool, nodes_to_create[name], cycle.runners[name]
+            ): name
+            for pool in CFG["pools"]
+            if (name := get_pool_name(pool)) in nodes_to_create
+        }
+
+        for future in concurrent.futures.as_completed(future_to_pool):
+            name = future_to_pool[future]
+            try:
+                future.result()
+            except Exception as e:
+                app.logger.exception("%s: Failed to maintain pool", name)
+                failed.append(name)
+                record_pool_error(name, e)
+
+    with POOL_ERRORS_LOCK:
+        for name in list(POOL_ERRORS):
+            if name not in failed:
+                del POOL_ERRORS[name]
+    record_starvation(cycle, failed, nodes_to_create)
+    return failed
+
+
+def record_starvation(cycle: Plan, failed: list[str], nodes_to_create: dict[str, int]):
+    """Count the cycles each pool is left under its min_ready by failures.
+
+    A pool is starved when it is under its min_ready and fails to scale up, or
+    is limited by its image, flavor or quota.  Once this lasts for
+    ``starved_threshold`` cycles in a row, it is escalated.
+    """
+    threshold = CFG.get("starved_threshold", 3)
+    for name, states in cycle.runners.items():
+        below = states["idle"] + states["booting"] < cycle.min_ready[name]
+        limited = nodes_to_create.get(name, cycle.creates[name]) < cycle.creates[name]
+        with POOL_ERRORS_LOCK:
+            if not below:
+                cycles = STARVED_CYCLES.pop(name, 0)
+            elif name in failed or limited:
+                cycles = STARVED_CYCLES[name] = STARVED_CYCLES.get(name, 0) + 1
+            else:
+                continue
+
+        if not below and cycles >= threshold:
+            app.logger.info("%s: Pool reached its min_ready again", name)
+        elif cycles == threshold:
+            app.logger.warning(
+                "%s: Pool is starved, under its min_ready for %s cycles",
+                name,
+                cycles,
+            )
+            notify("pool_starved", pool=name, cycles=cycles)
+
+
+def record_pool_error(name: str, error: Exception):
+    with POOL_ERRORS_LOCK:
+        POOL_ERRORS[name] = {
+            "message": str(error),
+            "category": getattr(error, "category", "other"),
+            "time": datetime.now(timezone.utc).isoformat(),
+        }
+
+
+def get_inventory_hash(servers: list, runners: list) -> str:
+    inventory = (
+        sorted((server.id, server.status) for server in servers),
+        sorted((runner_key(runner), runner.status, runner.busy) for runner in runners),
+    )
+    return hashlib.sha256(repr(inventory).encode("utf-8")).hexdigest()
+
+
+def needs_clean_up(servers: list, runners: list) -> bool:
+    """Check if a cycle should clean up, with ``full_reconcile_every`` set.
+
+    The clean-up is skipped while the servers and runners are the same as in
+    the last cycle which cleaned up, except every that many cycles, since some
+    deletions only depend on time passing (such as ``max_age_secs``).
+    """
+    global LAST_INVENTORY_HASH, SKIPPED_CLEAN_UPS
+
+    every = CFG.get("full_reconcile_every")
+    inventory_hash = get_inventory_hash(servers, runners)
+    if (
+        every is not None
+        and inventory_hash == LAST_INVENTORY_HASH
+        and SKIPPED_CLEAN_UPS + 1 < every
+    ):
+        SKIPPED_CLEAN_UPS += 1
+        return False
+
+    LAST_INVENTORY_HASH, SKIPPED_CLEAN_UPS = inventory_hash, 0
+    return True
+
+
+def clean_up(cycle: Plan, servers: list) -> int:
+    """Apply the deletions of a plan, also deleting orphaned volumes.
+
+    A failure to delete one of them doesn't stop the others from being
+    deleted, and the number of failures is returned.
+    """
+    for deletion in cycle.held_deletes:
+        app.logger.info(
+            "%s: Not deleting runner %s, pool recently scaled up",
+            get_pool_name(deletion.pool),
+            deletion.runner.name,
+        )
+    for deletion in cycle.protected_deletes:
+        app.logger.info(
+            "%s: Not deleting idle runner %s, a queued job may need it",
+            get_pool_name(deletion.pool),
+            deletion.runner.name,
+        )
+
+    # NOTE: Deletions are applied concurrently, each of them still removing
+    #       the runner before its server.
+    results = collections.Counter()
+    with ThreadPoolExecutor(max_workers=CFG.get("delete_concurrency", 4)) as executor:
+        for result in executor.map(apply_deletion, cycle.deletes):
+            results[result] += 1
+    failures, orphans, drained = (results[k] for k in ("failed", "orphan", "drained"))
+
+    # Clean-up volumes left behind by servers which are gone, leaving alone the
+    # recent ones since they might be waiting for their server to be created
+    names = {server_key(server)[1] for server in servers}
+    try:
+        with openstack_errors():
+            volumes = [
+                volume
+                for volume in list_volumes()
+                if volume.metadata["gha-runner-name"] not in names
+                and volume.status == "available"
+                and (get_age(volume.created_at) or 0) > 900
+            ]
+        delete_volumes(volumes, "orphaned_volume")
+    except Error as e:
+        app.logger.error("Failed to delete orphaned volumes: %s", e)
+        failures += 1
+
+    if CFG["openstack"].get("network_sweep_limit"):
+        try:
+            failures += sweep_network_resources(servers)
+        except Error as e:
+            app.logger.error("Failed to list orphaned network resources: %s", e)
+            failures += 1
+
+    if drained:
+        notify("scale_down", count=drained)
+    if orphans:
+        notify("orphan_cleanup", count=orphans)
+
+    app.logger.info(
+        "Cleaned up %s orphans, drained %s runners, %s deletes failed",
+        orphans,
+        drained,
+        failures,
+    )
+    return failures
+
+
+def sweep_network_resources(servers: list) -> int:
+    """Delete the ports and floating IPs leaked by this deployment.
+
+    Only the ones described with its managed tag are candidates, once they are
+    attached to none of the servers and older than 15 minutes, since they might
+    be waiting for their server to be created.  Up to ``network_sweep_limit``
+    of them are deleted per cycle, and the number of failures is returned.
+    """
+    description = get_managed_tag(CFG)
+    server_ids = {server.id for server in servers}
+    port_ids = {
+        port_id
+        for server in servers
+        for port_id in ((server.metadata or {}).get("gha-ports") or "").split(",")
+    }
+    with openstack_errors():
+        with timed("openstack", "list_ports"):
+            resources = [
+                ("port", port)
+                for port in CLOUD.network.ports(description=description)
+                if port.description == description
+                and port.device_id not in server_ids
+                and port.id not in port_ids
+                and (get_age(port.created_at) or 0) > 900
+            ]
+        with timed("openstack", "list_floating_ips"):
+            resources += [
+                ("floating_ip", ip)
+                for ip in CLOUD.network.ips(description=description)
+                if ip.description == description
+                and not ip.port_id
+                and (get_age(ip.created_at) or 0) > 900
+            ]
+
+    failures = 0
+    for kind, resource in resources[: CFG["openstack"]["network_sweep_limit"]]:
+        action, reason = "delete_%s" % kind, "orphaned_%s" % kind
+        fields = {"%s_id" % kind: resource.id}
+        if DRY_RUN:
+            app.logger.info("[dry-run] Would delete %s %s", kind, resource.id)
+            audit(action, "dry_run", reason, **fields)
+            continue
+
+        app.logger.info("Deleting orphaned %s %s", kind, resource.id)
+        try:
+            with openstack_errors(), timed("openstack", action):
+                if kind == "port":
+                    CLOUD.network.delete_port(resource)
+                else:
+                    CLOUD.network.delete_ip(resource)
+        except OpenStackError as e:
+            app.logger.error("Failed to delete %s %s: %s", kind, resource.id, e)
+            audit(action, "failure", reason, error=str(e), **fields)
+            failures += 1
+            continue
+        audit(action, "success", reason, **fields)
+    return failures
+
+
+def apply_deletion(deletion: Deletion) -> str | None:
+    """Apply a deletion of a plan, returning what it was.
+
+    That is ``orphan`` or ``drained`` once deleted, ``failed``, or None if
+    its runner is busy.
+    """
+    server, runner = deletion.server, deletion.runner
+    try:
+        if server is None:
+            if not delete_runner(runner, deletion.reason):
+                app.logger.error("Failed to delete runner %s", runner.name)
+                return "failed"
+            result = "orphan"
+        elif not delete_node(server, runner, deletion.reason):
+            result = None
+        elif runner is None:
+            result = "orphan"
+        else:
+            result = "drained"
+            if deletion.pool is not None:
+                record_scale(deletion.pool, "down")
+    except Error as e:
+        if runner is None:
+            app.logger.error("Failed to delete server %s: %s", server.name, e)
+        else:
+            app.logger.error("Failed to delete runner %s: %s", runner.name, e)
+        return "failed"
+
+    if runner is not None:
+        SEEN_ONLINE.discard(runner_key(runner))
+    return result
+
+
+def is_cloud_available() -> bool:
+    return CLOUD_RETRY_AT is None or time.monotonic() >= CLOUD_RETRY_AT
+
+
+def record_cloud_result(error: OpenStackError | None):
+    """Open or close the circuit breaker of the cloud after listing servers.
+
+    Once ``failure_threshold`` cycles in a row failed, the cloud isn't tried
+    again for 30 seconds, doubling with every further failure up to
+    ``max_backoff_secs``.  The first success closes it again.
+    """
+    global CLOUD_FAILURES, CLOUD_RETRY_AT
+
+    if error is None:
+        if CLOUD_RETRY_AT is not None:
+            app.logger.info("Cloud is available again")
+        CLOUD_FAILURES, CLOUD_RETRY_AT = 0, None
+        return
+
+    CLOUD_FAILURES += 1
+    threshold = CFG["openstack"].get("failure_threshold", 3)
+    if CLOUD_FAILURES < threshold:
+        return
+
+    backoff = min(
+        30 * 2 ** (CLOUD_FAILURES - threshold),
+        CFG["openstack"].get("max_backoff_secs", 600),
+    )
+    CLOUD_RETRY_AT = time.monotonic() + backoff
+    app.logger.error(
+        "Cloud failed %s cycles in a row, not trying it for %s seconds: %s",
+        CLOUD_FAILURES,
+        backoff,
+        error,
+    )
+    if CLOUD_FAILURES == threshold:
+        notify("cycle_error", error="cloud unavailable: %s" % error)
+
+
+def record_delete_failures(failures: int):
+    global DELETE_FAILURES, DELETE_FAILURE_CYCLES
+
+    DELETE_FAILURES += failures
+    DELETE_FAILURE_CYCLES = DELETE_FAILURE_CYCLES + 1 if failures else 0
+    if failures:
+        notify("delete_error", count=failures, cycles=DELETE_FAILURE_CYCLES)
+
+
+def is_degraded() -> bool:
+    threshold = CFG.get("delete_failure_threshold")
+    return threshold is not None and DELETE_FAILURE_CYCLES >= threshold
+
+
+def record_instance(pool: dict, server):
+    with INSTANCES_LOCK:
+        INSTANCES[server.id] = {
+            "name": server.name,
+            "github": get_github_name(pool),
+            "pool": get_pool_name(pool),
+            "created_at": datetime.now(timezone.utc).isoformat(),
+        }
+
+
+def reconcile_instances(known: set[str], servers: list, runners: list):
+    """Bring the known instances in line with the servers which exist.
+
+    Instances which were known when the servers were listed but are not among
+    them are gone, while servers which are not known yet (e.g. created before
+    the state file was configured) are added, attributed to the pool of their
+    runner.  Instances created since the servers were listed are kept.
+    """
+    servers_by_key = {server_key(server): server for server in servers}
+    pools = {
+        runner_key(runner): get_pool_for_runner(
+            runner, server=servers_by_key.get(runner_key(runner))
+        )
+        for runner in runners
+    }
+
+    # NOTE: Runners which still have no server once they are expired are
+    #       deleted as orphans by the clean-up, there is no need to keep
+    #       them around.
+    expired = get_expired_registrations()
+
+    with INSTANCES_LOCK:
+        for server_id in known - {server.id for server in servers}:
+            INSTANCES.pop(server_id, None)
+        for name in expired:
+            REGISTRATIONS.pop(name, None)
+
+        for server in servers:
+            if server.id in INSTANCES:
+                continue
+
+            github_name, name = server_key(server)
+            pool = pools.get((github_name, name))
+            INSTANCES[server.id] = {
+                "name": name,
+                "github": github_name,
+                "pool": get_pool_name(pool) if pool else None,
+                "created_at": getattr(server, "created_at", None),
+            }
+
+
+def get_persisted_servers() -> list:
+    with INSTANCES_LOCK:
+        return [
+            types.SimpleNamespace(
+                id=server_id,
+                name=instance["name"],
+                status="UNKNOWN",
+                metadata={
+                    "gha-managed-by": get_deployment_id(CFG),
+                    "gha-github": instance["github"],
+                    "gha-runner-name": instance["name"],
+                    "gha-pool": instance["pool"],
+                    "gha-created": instance["created_at"],
+                },
+            )
+            for server_id, instance in INSTANCES.items()
+        ]
+
+
+def load_state():
+    path = CFG.get("state_file")
+    if not path or not os.path.exists(path):
+        return
+
+    try:
+        with open(path, "r", encoding="utf-8") as f:
+            state = json.load(f)
+    except (OSError, ValueError) as e:
+        app.logger.warning("Failed to load state from %s: %s", path, e)
+        return
+
+    with INSTANCES_LOCK:
+        INSTANCES.update(state.get("instances", {}))
+        REGISTRATIONS.update(state.get("registrations", {}))
+    SEEN_ONLINE.update(tuple(key) for key in state.get("seen_online", []))
+    FIRST_SEEN.update(
+        ((github_name, name), first_seen)
+        for github_name, name, first_seen in state.get("first_seen", [])
+    )
+
+
+def save_state():
+    path = CFG.get("state_file")
+    if not path:
+        return
+
+    with INSTANCES_LOCK:
+        state = {
+            "instances": dict(INSTANCES),
+            "registrations": dict(REGISTRATIONS),
+            "seen_online": sorted(SEEN_ONLINE),
+            "first_seen": sorted(key + (seen,) for key, seen in FIRST_SEEN.items()),
+        }
+
+    # NOTE: The state is written to a temporary file first, so that a crash
+    #       while writing it never leaves a truncated state file behind.
+    try:
+        with STATE_FILE_LOCK:
+            with open(path + ".tmp", "w", encoding="utf-8") as f:
+                json.dump(state, f)
+            os.replace(path + ".tmp", path)
+    except OSError as e:
+        app.logger.warning("Failed to save state to %s: %s", path, e)
+
+
+def record_registration(pool: dict, name: str):
+    with INSTANCES_LOCK:
+        REGISTRATIONS[name] = {
+            "github": get_github_name(pool),
+            "created_at": datetime.now(timezone.utc).isoformat(),
+        }
+    save_state()
+
+
+def discard_registration(pool: dict, name: str, reason: str = "create_failed"):
+    """Delete the runner registered for a server which couldn't be created.
+
+    Servers registering their runner with a registration token have none yet,
+    so they are only forgotten.  If the runner can't be deleted, it is left to
+    the maintenance cycles, which delete it as an orphan once it is older than
+    ``registration_timeout_secs``.
+    """
+    github_cfg = get_github_config(pool)
+    fields = {"runner": name, "github": get_github_name(pool)}
+    if get_registration(github_cfg) == "jit":
+        runners_path = "/orgs/%s/actions/runners" % github_cfg["org"]
+        try:
+            response = github_request(
+                github_cfg, "GET", runners_path, params={"name": name}
+            )
+            for runner in response.json().get("runners", []):
+                if runner.get("name") == name:
+                    github_request(
+                        github_cfg, "DELETE", "%s/%s" % (runners_path, runner["id"])
+                    )
+                    audit("delete_runner", "success", reason, **fields)
+        except GitHubError as e:
+            app.logger.error("Failed to delete runner %s: %s", name, e)
+            audit("delete_runner", "failure", reason, error=str(e), **fields)
+            return
+
+    with INSTANCES_LOCK:
+        REGISTRATIONS.pop(name, None)
+    save_state()
+
+
+def get_expired_registrations() -> dict[str, dict]:
+    timeout = CFG.get("registration_timeout_secs", 600)
+    with INSTANCES_LOCK:
+        return {
+            name: registration
+            for name, registration in REGISTRATIONS.items()
+            if (get_age(registration["created_at"]) or 0) > timeout
+        }
+
+
+def reap_registrations():
+    """Delete the runners which were registered but never got a server.
+
+    This catches the runners left behind when the controller stopped between
+    registering a runner and creating its server, once they were registered
+    more than ``registration_timeout_secs`` ago.  Runners which are still
+    within that time are left to the maintenance cycles.
+    """
+    expired = get_expired_registrations()
+    if not expired:
+        return
+
+    try:
+        with openstack_errors():
+            servers = {server_key(server) for server in list_servers()}
+        runners = {}
+        github_names = {registration["github"] for registration in expired.values()}
+        for github_name in github_names:
+            for runner in list_runners(github_name):
+                runners[runner_key(runner)] = runner
+    except Error as e:
+        app.logger.error("Failed to reap runners registered without a server: %s", e)
+        return
+
+    for name, registration in expired.items():
+        key = (registration["github"], name)
+        runner = runners.get(key)
+        if runner is not None and key not in servers:
+            app.logger.warning(
+                "Runner %s was registered at %s but has no server, deleting it",
+                name,
+                registration["created_at"],
+            )
+            try:
+                delete_runner(runner, "orphaned_registration")
+            except Error as e:
+                app.logger.error("Failed to delete runner %s: %s", name, e)
+                continue
+        with INSTANCES_LOCK:
+            REGISTRATIONS.pop(name, None)
+    save_state()
+
+
+def list_servers() -> list:
+    # NOTE: Filtering by tag on the server side only returns the servers of
+    #       this deployment, but leaves out the ones created before they were
+    #       tagged, so it is opt-in.
+    query = {}
+    if CFG["openstack"].get("filter_by_tag", False):
+        query["tags"] = get_managed_tag(CFG)
+    with timed("openstack", "list_servers"):
+        servers = list(CLOUD.compute.servers(**query))
+    return [server for server in servers if is_managed(server)]
+
+
+def is_managed(server) -> bool:
+    """Check if a server is managed by this deployment of the controller.
+
+    Servers are marked with ``gha-managed-by`` metadata when they are created,
+    so servers of other deployments or created by hand are left alone even if
+    their name looks like one of ours.  Servers created before the marker was
+    set are recognized by their name only.
+    """
+    metadata = getattr(server, "metadata", None) or {}
+    if "gha-managed-by" in metadata:
+        return metadata["gha-managed-by"] == get_deployment_id(CFG)
+
+    if not is_generated_name(CFG, server.name):
+        return False
+
+    if server.id not in LEGACY_SERVERS:
+        LEGACY_SERVERS.add(server.id)
+        app.logger.warning(
+            "Server %s has no 'gha-managed-by' metadata, managing it by its name",
+            server.name,
+        )
+    return True
+
+
+def update_status(servers: list, runners: list):
+    """Update the snapshot served by /status from this cycle's inventory.
+
+    Servers are attributed to a pool through the runner with the same name,
+    the ones without a runner are counted as unassigned.
+    """
+    global STATUS
+
+    pools = {}
+    for pool in CFG["pools"]:
+        pools[get_pool_name(pool)] = {
+            "label": pool["runner"]["label"],
+            "min_ready": get_min_ready(pool, datetime.now(timezone.utc)),
+            "degraded": DEGRADED_POOLS.get(get_pool_name(pool), (None,))[0],
+            "last_error": POOL_ERRORS.get(get_pool_name(pool)),
+            "starved_cycles": STARVED_CYCLES.get(get_pool_name(pool), 0),
+            "queued_jobs": get_queued_jobs(pool),
+            "max_concurrent_builds": pool.get("max_concurrent_builds"),
+            "runners": {"idle": 0, "busy": 0, "booting": 0, "offline": 0},
+            "instances": {},
+        }
+
+    servers_by_key = {server_key(server): server for server in servers}
+    runners_by_key = {}
+    for runner in runners:
+        server = servers_by_key.get(runner_key(runner))
+        pool = get_pool_for_runner(runner, server=server)
+        if pool is None:
+            continue
+        runners_by_key[runner_key(runner)] = get_pool_name(pool)
+        pools[get_pool_name(pool)]["runners"][classify_runner(runner)] += 1
+
+    with PENDING_CREATIONS_LOCK:
+        for name, pool in pools.items():
+            runners_by_state = pool["runners"]
+            pool["concurrent_builds"] = PENDING_CREATIONS[name] + sum(
+                runners_by_state[state] for state in ("idle", "busy", "booting")
+            )
+
+    unassigned = {}
+    for server in servers:
+        name = runners_by_key.get(server_key(server))
+        instances = pools[name]["instances"] if name else unassigned
+        instances[server.status] = instances.get(server.status, 0) + 1
+
+    STATUS = {
+        "last_cycle": datetime.now(timezone.utc).isoformat(),
+        "degraded": is_degraded(),
+        "delete_failures": DELETE_FAILURES,
+        "delete_failure_cycles": DELETE_FAILURE_CYCLES,
+        "cloud": "available" if is_cloud_available() else "unavailable",
+        "pools": pools,
+        "unassigned_instances": unassigned,
+        "debug_holds": DEBUG_HOLDS,
+    }
+
+
+@app.route("/status")
+def status():
+    return jsonify(STATUS)
+
+
+@app.route("/metrics")
+def metrics():
+    """Serve the request latencies in the Prometheus text format."""
+    lines = []
+    with METRICS_LOCK:
+        for api in ("github", "openstack"):
+            name = "gha_%s_request_seconds" % api
+            lines.append("# TYPE %s histogram" % name)
+            for (histogram_api, op), histogram in sorted(LATENCIES.items()):
+                if histogram_api != api:
+                    continue
+                for bound, count in zip(LATENCY_BUCKETS, histogram["buckets"]):
+                    lines.append(
+                        '%s_bucket{op="%s",le="%s"} %d' % (name, op, bound, count)
+                    )
+                lines.append(
+                    '%s_bucket{op="%s",le="+Inf"} %d' % (name, op, histogram["count"])
+                )
+                lines.append('%s_sum{op="%s"} %f' % (name, op, histogram["sum"]))
+                lines.append('%s_count{op="%s"} %d' % (name, op, histogram["count"]))
+
+            name = "gha_%s_request_errors_total" % api
+            lines.append("# TYPE %s counter" % name)
+            for (errors_api, op), count in sorted(REQUEST_ERRORS.items()):
+                if errors_api == api:
+                    lines.append('%s{op="%s"} %d' % (name, op, count))
+
+    lines.append("# TYPE gha_github_unlabeled_runners gauge")
+    with METRICS_LOCK:
+        for github_name, count in sorted(UNLABELED_RUNNERS.items()):
+            lines.append(
+                'gha_github_unlabeled_runners{github="%s"} %d' % (github_name, count)
+            )
+
+    lines.append("# TYPE gha_github_ratelimit_remaining gauge")
+    with RATE_LIMITS_LOCK:
+        for github_name, (remaining, _) in sorted(RATE_LIMITS.items()):
+            lines.append(
+                'gha_github_ratelimit_remaining{github="%s"} %d'
+                % (github_name, remaining)
+            )
+
+    lines.append("# TYPE gha_pool_starved gauge")
+    threshold = CFG.get("starved_threshold", 3)
+    with POOL_ERRORS_LOCK:
+        for pool in CFG["pools"]:
+            name = get_pool_name(pool)
+            starved = STARVED_CYCLES.get(name, 0) >= threshold
+            lines.append('gha_pool_starved{pool="%s"} %d' % (name, starved))
+
+    # NOTE: The value is the time of the error, and the series goes away once
+    #       the pool scales up again.
+    lines.append("# TYPE gha_pool_last_error gauge")
+    with POOL_ERRORS_LOCK:
+        for pool, error in sorted(POOL_ERRORS.items()):
+            lines.append(
+                'gha_pool_last_error{pool="%s",category="%s"} %d'
+                % (
+                    pool,
+                    error["category"],
+                    datetime.fromisoformat(error["time"]).timestamp(),
+                )
+            )
+
+    return "\n".join(lines) + "\n", 200, {"Content-Type": "text/plain; version=0.0.4"}
+
+
+@app.route("/readyz")
+def readyz():
+    if is_degraded():
+        return "degraded", 503
+    return "ok"
+
+
+@app.route("/admin/pools/<name>/scale", methods=["POST"])
+def admin_scale(name):
+    """Scale a pool by a ``delta`` or to a ``count`` of servers, on demand.
+
+    This is guarded by the token in ``admin.token``, and disabled without it.
+    Scaling up is still held back by ``max_ready`` and ``max_total_instances``,
+    and scaling down only deletes servers with an idle runner.
+    """
+    token = (CFG.get("admin") or {}).get("token")
+    if not token:
+        return jsonify(error="admin endpoints are disabled"), 404
+    authorization = request.headers.get("Authorization", "")
+    if not hmac.compare_digest(authorization, "Bearer " + token):
+        return jsonify(error="invalid admin token"), 401
+
+    pool = get_pool(name)
+    if pool is None:
+        return jsonify(error="unknown pool '%s'" % name), 404
+
+    body = request.get_json(silent=True) or {}
+    delta, count = body.get("delta"), body.get("count")
+    if (delta is None) == (count is None):
+        return jsonify(error="expected either 'delta' or 'count'"), 400
+    value = delta if count is None else count
+    if not isinstance(value, int) or (count is not None and count < 0):
+        return jsonify(error="'delta' or 'count' is not a valid number"), 400
+
+    with openstack_errors():
+        servers = list_servers()
+    servers_by_key = {server_key(server): server for server in servers}
+    runners = [
+        runner
+        for runner in list_runners(get_github_name(pool))
+        if get_pool_for_runner(runner, server=servers_by_key.get(runner_key(runner)))
+        is pool
+        and classify_runner(runner) != "offline"
+    ]
+
+    target = count if count is not None else len(runners) + delta
+    summary = {
+        "pool": name,
+        "current": len(runners),
+        "target": max(target, 0),
+        "created": 0,
+        "deleted": 0,
+        "limited_by": [],
+        "errors": [],
+    }
+
+    if target > len(runners):
+        nodes_to_create = target - len(runners)
+
+        states = collections.Counter(classify_runner(r) for r in runners)
+        max_ready = pool.get("max_ready")
+        ready = states["idle"] + states["booting"]
+        if max_ready is not None and ready + nodes_to_create > max_ready:
+            nodes_to_create = max(max_ready - ready, 0)
+            summary["limited_by"].append("max_ready")
+
+        max_concurrent_builds = pool.get("max_concurrent_builds")
+        if (
+            max_concurrent_builds is not None
+            and len(runners) + nodes_to_create > max_concurrent_builds
+        ):
+            nodes_to_create = max(max_concurrent_builds - len(runners), 0)
+            summary["limited_by"].append("max_concurrent_builds")
+
+        max_total_instances = CFG.get("max_total_instances")
+        if (
+            max_total_instances is not None
+            and len(servers) + nodes_to_create > max_total_instances
+        ):
+            nodes_to_create = max(max_total_instances - len(servers), 0)
+            summary["limited_by"].append("max_total_instances")
+
+        with pending_creations(pool, nodes_to_create, states) as reserved:
+            if reserved < nodes_to_create:
+                summary["limited_by"].append("pending_creations")
+            if reserved:
+                created, errors = spawn_nodes(pool, reserved, "admin")
+                summary["created"] = created
+                summary["errors"] = [str(e) for e in errors]
+
+    # NOTE: As many idle runners as the pool has queued jobs are kept for them.
+    idle = [r for r in runners if classify_runner(r) == "idle"]
+    nodes_to_delete = max(len(runners) - target, 0)
+    deletable = idle[get_queued_jobs(pool) :]
+    if len(deletable) < min(len(idle), nodes_to_delete):
+        summary["limited_by"].append("queued_jobs")
+    for runner in deletable[:nodes_to_delete]:
+        server = servers_by_key.get(runner_key(runner))
+        if server is None:
+            continue
+        try:
+            if delete_node(server, runner, "admin"):
+                summary["deleted"] += 1
+        except Error as e:
+            summary["errors"].append(str(e))
+    if summary["deleted"]:
+        record_scale(pool, "down")
+        notify("scale_down", pool=name, count=summary["deleted"])
+
+    app.logger.info("Scaled %s on demand: %s", name, summary)
+    return jsonify(summary)
+
+
+def should_delete_runner(runner, server, pool: dict, snapshot: Snapshot) -> bool:
+    """Decide if a runner and the server backing it should be deleted.
+
+    If the runner belongs to an ephemeral pool, it is deleted as soon as it
+    goes offline after having been seen online, since it has already run its
+    only job.  This skips the grace of waiting for GitHub to unregister it and
+    for the next cycle to notice that the server no longer has a runner.
+
+    Runners which are not busy are also deleted once their server is older
+    than the ``max_age_secs`` of their pool, so that they get recycled.
+    """
+    if is_expired(server, pool, snapshot.now) and not runner.busy:
+        return True
+
+    if not pool["runner"].get("ephemeral", False):
+        return False
+
+    return classify_runner(runner, snapshot.seen_online) == "offline"
+
+
+def is_expired(server, pool: dict, now: datetime | None = None) -> bool:
+    max_age_secs = pool.get("max_age_secs")
+    if max_age_secs is None:
+        return False
+
+    # NOTE: Servers whose age cannot be told are taken as old enough to be
+    #       recycled, rather than being kept forever.
+    age = get_server_age(server, now)
+    return age is None or age > max_age_secs
+
+
+@traced
+def delete_node(server, runner=None, reason: str = "unknown") -> bool:
+    """Delete a server, draining the runner linked to it first.
+
+    The runner is unregistered from GitHub before the server is deleted so
+    that no job can be assigned to it while it is being torn down.  If the
+    runner picked up a job in the meantime, GitHub refuses to remove it and
+    the server is left alone, while a runner which GitHub already removed
+    counts as drained.
+    """
+    set_span_attributes(server=server.name, runner=getattr(runner, "name", None))
+    fields = {"server": server.name, "server_id": server.id}
+    failed = is_failed(server, runner)
+
+    if runner is not None:
+        runner = get_current_runner(runner)
+    if runner is not None:
+        if runner.busy:
+            app.logger.info("Runner %s is busy, not deleting it", runner.name)
+            audit("delete_server", "skipped", reason, detail="runner is busy", **fields)
+            return False
+
+        if not delete_runner(runner, reason):
+            app.logger.info(
+                "Failed to drain runner %s, not deleting server %s",
+                runner.name,
+                server.name,
+            )
+            return False
+
+    if DRY_RUN:
+        app.logger.info("[dry-run] Would delete server %s", server.name)
+        audit("delete_server", "dry_run", reason, **fields)
+        return True
+
+    if CFG.get("capture_console_on_delete", False) and failed:
+        capture_console_output(server, reason)
+
+    app.logger.info("Deleting server %s", server.name)
+    with OPENSTACK_SEMAPHORE:
+        with openstack_errors():
+            volumes = list_volumes(server)
+        try:
+            with openstack_errors(), timed("openstack", "delete_server"):
+                CLOUD.compute.delete_server(server)
+        except OpenStackError as e:
+            audit("delete_server", "failure", reason, error=str(e), **fields)
+            raise
+        audit("delete_server", "success", reason, **fields)
+
+    # NOTE: Waiting is opt-in since it slows down deleting many servers, but
+    #       it makes sure the next cycle doesn't see the server anymore.
+    #       Volumes can only be deleted once they are detached, so it is
+    #       always done for servers which have some.  The semaphore isn't held
+    #       meanwhile, so that other calls to OpenStack aren't held up.
+    delete_timeout = CFG["openstack"].get("delete_timeout")
+    if volumes and not delete_timeout:
+        delete_timeout = 300
+    deleted = True
+    if delete_timeout:
+        with openstack_errors():
+            try:
+                CLOUD.compute.wait_for_delete(server, interval=2, wait=delete_timeout)
+            except openstack.exceptions.ResourceTimeout:
+                deleted = False
+
+    with OPENSTACK_SEMAPHORE:
+        # NOTE: Volumes still attached to a server which isn't gone yet are
+        #       left to the clean-up of orphaned volumes, which deletes them
+        #       once they are detached.  Ports can be deleted either way.
+        if deleted:
+            delete_volumes(volumes, reason)
+        else:
+            app.logger.warning(
+                "Server %s was not deleted after %s seconds, leaving %s volumes "
+                "to be cleaned up later",
+                server.name,
+                delete_timeout,
+                len(volumes),
+            )
+        ports = (server.metadata or {}).get("gha-ports")
+        if ports:
+            delete_ports(ports.split(","), reason)
+
+    return True
+
+
+def is_failed(server, runner=None) -> bool:
+    """Check if a server failed, rather than being scaled down normally.
+
+    Servers in ERROR or still building failed to boot, as did the ones whose
+    runner never came online.
+    """
+    if server.status in ("ERROR", "BUILD"):
+        return True
+    return (
+        runner is not None
+        and runner.status != "online"
+        and runner_key(runner) not in SEEN_ONLINE
+    )
+
+
+def capture_console_output(server, reason: str):
+    """Log the console output of a server before it is deleted.
+
+    It is also written to ``console_log_dir`` if set.  Failing to get it
+    doesn't prevent the server from being deleted.
+    """
+    path = CFG.get("console_log_dir")
+    try:
+        with OPENSTACK_SEMAPHORE, openstack_errors():
+            with timed("openstack", "get_console_output"):
+                output = CLOUD.compute.get_server_console_output(
+                    server, length=None if path else CONSOLE_LOG_LINES
+                )
+    except OpenStackError as e:
+        app.logger.warning(
+            "Failed to get the console output of server %s: %s", server.name, e
+        )
+        return
+
+    output = (output or {}).get("output") or ""
+    app.logger.warning(
+        "Console output of server %s (%s, deleted for %s):\n%s",
+        server.name,
+        server.status,
+        reason,
+        "\n".join(output.splitlines()[-CONSOLE_LOG_LINES:]),
+    )
+    if path is None:
+        return
+
+    try:
+        with open(
+            os.path.join(path, "%s.log" % server.name), "w", encoding="utf-8"
+        ) as f:
+            f.write(output)
+    except OSError as e:
+        app.logger.warning(
+            "Failed to store the console output of server %s: %s", server.name, e
+        )
+
+
+def list_volumes(server=None) -> list:
+    """List the volumes created along with a server, or with any server.
+
+    Only volumes with our metadata are returned, so volumes which were not
+    created by this deployment of the controller are never touched.
+    """
+    if not any(pool["instance"].get("volumes") for pool in CFG["pools"]):
+        return []
+
+    name = server_key(server)[1] if server is not None else None
+    with timed("openstack", "list_volumes"):
+        volumes = CLOUD.list_volumes()
+    return [
+        volume
+        for volume in volumes
+        if (volume.metadata or {}).get("gha-managed-by") == get_deployment_id(CFG)
+        and name in (None, volume.metadata.get("gha-runner-name"))
+    ]
+
+
+def get_age(timestamp: str | None, now: datetime | None = None) -> float | None:
+    """Return how many seconds ago a timestamp was, or None if it is invalid."""
+    try:
+        created_at = datetime.fromisoformat(timestamp.replace("Z", "+00:00"))
+    except (AttributeError, ValueError):
+        return None
+
+    if created_at.tzinfo is None:
+        created_at = created_at.replace(tzinfo=timezone.utc)
+    return ((now or datetime.now(timezone.utc)) - created_at).total_seconds()
+
+
+def get_server_age(server, now: datetime | None = None) -> float | None:
+    """Return the age of a server, preferring its ``gha-created`` metadata.
+
+    Servers without it, or with an invalid one, fall back to the creation time
+    reported by OpenStack, and None is returned if neither is valid.
+    """
+    for timestamp in (
+        (server.metadata or {}).get("gha-created"),
+        getattr(server, "created_at", None),
+    ):
+        age = get_age(timestamp, now)
+        if age is not None:
+            return age
+
+    if server.id not in UNDATED_SERVERS:
+        UNDATED_SERVERS.add(server.id)
+        app.logger.warning("Server %s has no valid creation time", server.name)
+    return None
+
+
+# NOTE: Ids of the servers without a valid creation time, so that they are
+#       only warned about once.
+UNDATED_SERVERS: set[str] = set()
+
+
+def delete_volumes(volumes: list, reason: str):
+    for volume in volumes:
+        fields = {"volume": volume.name, "volume_id": volume.id}
+        if volume.metadata.get("gha-delete-on-termination") != "true":
+            app.logger.info("Keeping volume %s", volume.name)
+            audit("delete_volume", "skipped", reason, **fields)
+            continue
+
+        app.logger.info("Deleting volume %s", volume.name)
+        try:
+            with openstack_errors(), timed("openstack", "delete_volume"):
+                CLOUD.delete_volume(volume.id)
+        except OpenStackError as e:
+            audit("delete_volume", "failure", reason, error=str(e), **fields)
+            raise
+        audit("delete_volume", "success", reason, **fields)
+
+
+@traced
+def get_current_runner(runner):
+    """Fetch a runner again from GitHub, or return None if it is gone.
+
+    Ephemeral runners are removed by GitHub once they ran their job, which
+    may happen between listing them and deleting their server, so this means
+    the runner is already drained.
+    """
+    github_name = runner.github_name
+    try:
+        with github_errors():
+            org = get_organization(github_name)
+            with timed("github", "get_runner"):
+                current = org.get_self_hosted_runner(runner.id)
+    except GitHubError as e:
+        if e.status != 404:
+            raise
+        app.logger.info("Runner %s is already gone from GitHub", runner.name)
+        return None
+    current.github_name = github_name
+    return current
+
+
+def delete_runner(runner, reason: str = "unknown") -> bool:
+    set_span_attributes(runner=runner.name)
+    fields = {
+        "runner": runner.name,
+        "runner_id": runner.id,
+        "github": runner.github_name,
+    }
+
+    if DRY_RUN:
+        app.logger.info("[dry-run] Would delete runner %s", runner.name)
+        audit("delete_runner", "dry_run", reason, **fields)
+        return True
+
+    app.logger.info("Deleting runner %s", runner.name)
+    try:
+        with github_errors():
+            org = get_organization(runner.github_name)
+            with timed("github", "remove_runner"):
+                deleted = org.remove_self_hosted_runner(runner)
+    except GitHubError as e:
+        audit("delete_runner", "failure", reason, error=str(e), **fields)
+        raise
+    audit("delete_runner", "success" if deleted else "failure", reason, **fields)
+    return deleted
+
+
+def get_organization(github_name: str):
+    org = get_github_configs(CFG)[github_name]["org"]
+    with timed("github", "get_organization"):
+        return GITHUBS[github_name].get_organization(org)
+
+
+def list_runners(github_name: str) -> list:
+    """List the runners of an organization, tagged with its name."""
+    with github_errors():
+        runners = list(get_organization(github_name).get_runners())
+        remaining, _ = GITHUBS[github_name].rate_limiting
+        record_rate_limit(
+            github_name, remaining, GITHUBS[github_name].rate_limiting_resettime
+        )
+    for runner in runners:
+        runner.github_name = github_name
+
+    # NOTE: Runners whose name isn't one the controller generates, or which
+    #       the label policy doesn't allow, are left out so that nothing ever
+    #       acts on them, whoever created them.  Runners listed without labels
+    #       are kept, their name being enough to tell they are ours, and are
+    #       attributed to their pool by name instead, whose labels the policy
+    #       is then checked against.
+    runners = [runner for runner in runners if is_generated_name(CFG, runner.name)]
+    unlabeled = [runner for runner in runners if not runner_labels(runner)]
+    with METRICS_LOCK:
+        UNLABELED_RUNNERS[github_name] = len(unlabeled)
+    if unlabeled:
+        app.logger.warning(
+            "%s runners of %s have no labels, attributing them by name: %s",
+            len(unlabeled),
+            github_name,
+            ", ".join(runner.name for runner in unlabeled),
+        )
+    return [
+        runner
+        for runner in runners
+        if is_allowed_by_label_policy(CFG, get_policy_labels(runner))
+    ]
+
+
+def get_policy_labels(runner) -> list[str]:
+    """Return the labels of a runner to check the label policy against.
+
+    Runners listed without labels get those of the pool they are attributed
+    to by name, or none if it isn't known.
+    """
+    labels = runner_labels(runner)
+    if labels:
+        return labels
+    pool = get_pool_for_unlabeled_runner(runner)
+    return get_runner_labels(pool) if pool is not None else []
+
+
+def record_rate_limit(github_name: str, remaining: int, reset: float):
+    with RATE_LIMITS_LOCK:
+        RATE_LIMITS[github_name] = (remaining, reset)
+
+
+def is_throttled(github_name: str) -> bool:
+    """Check if the GitHub rate limit of an organization is below its threshold.
+
+    This lasts until the rate limit resets, and never happens without a
+    ``rate_limit_threshold``.
+    """
+    threshold = get_github_configs(CFG)[github_name].get("rate_limit_threshold", 0)
+    with RATE_LIMITS_LOCK:
+        remaining, reset = RATE_LIMITS.get(github_name, (None, 0))
+    return remaining is not None and remaining < threshold and reset > time.time()
+
+
+def runner_key(runner) -> tuple[str, str]:
+    return (runner.github_name, runner.name)
+
+
+def server_key(server) -> tuple[str, str]:
+    # NOTE: Servers created before this metadata was set all belong to the
+    #       default organization and are named after their runner.
+    metadata = getattr(server, "metadata", None) or {}
+    return (
+        metadata.get("gha-github", "default"),
+        metadata.get("gha-runner-name", server.name),
+    )
+
+
+def get_server_pool(server, instances: dict[str, dict]) -> str | None:
+    """Return the label of the pool a server was created for, if known."""
+    label = (server.metadata or {}).get("gha-pool")
+    if label is None:
+        label = instances.get(server.id, {}).get("pool")
+    return label
+
+
+def get_pool_for_runner(
+    runner, pools: list[dict] | None = None, server=None
+) -> dict | None:
+    """Return the pool of a runner, found by its label.
+
+    When several pools share the runner's label, the pool is told apart by
+    the ``gha-pool`` metadata of the runner's server, if it is given.
+    """
+    labels = runner_labels(runner)
+    if not labels:
+        return get_pool_for_unlabeled_runner(runner, pools, server)
+
+    candidates = [
+        pool
+        for pool in get_pools_for_github(runner.github_name, pools)
+        if normalize_label(pool["runner"]["label"]) in labels
+    ]
+    if len(candidates) > 1 and server is not None:
+        name = (server.metadata or {}).get("gha-pool")
+        for pool in candidates:
+            if get_pool_name(pool) == name:
+                return pool
+    return candidates[0] if candidates else None
+
+
+def get_pool_for_unlabeled_runner(
+    runner, pools: list[dict] | None = None, server=None
+) -> dict | None:
+    """Return the pool of a runner listed without labels, found by its name.
+
+    That is the pool in the ``gha-pool`` metadata of the runner's server, if
+    it is given, or else the only pool with the longest name prefix which the
+    runner's name starts with.
+    """
+    candidates = [
+        pool
+        for pool in get_pools_for_github(runner.github_name, pools)
+        if runner.name.startswith(get_name_prefix(CFG, pool))
+    ]
+    if server is not None:
+        name = (server.metadata or {}).get("gha-pool")
+        for pool in candidates:
+            if get_pool_name(pool) == name:
+                return pool
+
+    longest = max((len(get_name_prefix(CFG, pool)) for pool in candidates), default=0)
+    candidates = [
+        pool for pool in candidates if len(get_name_prefix(CFG, pool)) == longest
+    ]
+    return candidates[0] if len(candidates) == 1 else None
+
+
+def get_nodes_to_create(
+    pool: dict,
+    states: dict[str, int],
+    queued_jobs: int,
+    warm: int = 0,
+    min_ready: int | None = None,
+) -> int:
+    if min_ready is None:
+        min_ready = pool["min_ready"]
+
+    # NOTE: Runners which are still booting will soon be idle, so they count
+    #       towards min_ready to avoid creating more servers on every cycle.
+    ready = states["idle"] + states["booting"]
+    nodes_to_create = max(min_ready - ready, pool.get("min_ready_instances", 0) - warm)
+
+    # NOTE: Pools with a max_ready also scale up with the number of queued
+    #       jobs, as long as they have no more than max_ready ready runners.
+    max_ready = pool.get("max_ready")
+    if max_ready is not None:
+        nodes_to_create = min(
+            max(nodes_to_create, get_runners_needed(states, queued_jobs)),
+            max_ready - ready,
+        )
+
+    # NOTE: Busy runners count towards max_concurrent_builds as well, since it
+    #       caps the jobs which can run at once rather than the ready runners.
+    max_concurrent_builds = pool.get("max_concurrent_builds")
+    if max_concurrent_builds is not None:
+        nodes_to_create = min(
+            nodes_to_create, max_concurrent_builds - ready - states["busy"]
+        )
+
+    return max(nodes_to_create, 0)
+
+
+def limit_nodes_to_create(pool: dict, nodes_to_create: int, allocated: dict) -> int:
+    """Limit the nodes planned for a pool to what its image, flavor and quota allow.
+
+    The quota left by the pools limited before is kept track of in ``allocated``.
+    """
+    degraded = check_instance_refs(pool)
+    set_pool_degraded(pool, degraded)
+    if degraded:
+        return 0
+
+    headroom = allocate_quota(pool, nodes_to_create, allocated)
+    if headroom < nodes_to_create:
+        app.logger.warning(
+            "%s: Quota only allows %s of %s nodes",
+            get_pool_name(pool),
+            headroom,
+            nodes_to_create,
+        )
+    return headroom
+
+
+def set_pool_degraded(pool: dict, reason: str | None):
+    """Mark a pool as degraded (or not), logging why at most every 10 minutes."""
+    name = get_pool_name(pool)
+    if reason is None:
+        DEGRADED_POOLS.pop(name, None)
+        return
+
+    last = DEGRADED_POOLS.get(name, (None, None))[1]
+    if last is None or time.monotonic() - last > 600:
+        app.logger.error("%s: Not scaling up, %s", name, reason)
+        last = time.monotonic()
+    DEGRADED_POOLS[name] = (reason, last)
+
+
+def get_queued_jobs(pool: dict) -> int:
+    with QUEUED_JOBS_LOCK:
+        return list(QUEUED_JOBS.values()).count(get_pool_name(pool))
+
+
+def distribute_budget(wanted: dict[str, int], budget: int) -> dict[str, int]:
+    """Share a number of nodes between pools, one node at a time.
+
+    Every pool which still wants nodes gets one in turn until the budget runs
+    out, so that a single pool with a large deficit can't starve the others.
+    """
+    granted = dict.fromkeys(wanted, 0)
+    while budget > 0:
+        pending = [name for name in sorted(wanted) if granted[name] < wanted[name]]
+        if not pending:
+            break
+
+        for name in pending[:budget]:
+            granted[name] += 1
+        budget -= len(pending[:budget])
+    return granted
+
+
+@traced
+def scale_up_pool(pool: dict, nodes_to_create: int, states: dict[str, int]):
+    set_span_attributes(pool=get_pool_name(pool), nodes_to_create=nodes_to_create)
+
+    with pending_creations(pool, nodes_to_create, states) as reserved:
+        if reserved < nodes_to_create:
+            app.logger.info(
+                "Limited to %s new nodes by servers already being created", reserved
+            )
+        if reserved <= 0:
+            return
+
+        _, errors = spawn_nodes(pool, reserved, "min_ready")
+    if errors:
+        raise errors[0]
+
+
+def spawn_nodes(pool: dict, nodes_to_create: int, reason: str) -> tuple[int, list]:
+    """Create servers for a pool, returning how many were created and errors."""
+    app.logger.info("Scaling up %s nodes", nodes_to_create)
+
+    created = 0
+    errors = []
+    spawn_concurrency = pool.get("spawn_concurrency", CFG.get("spawn_concurrency", 4))
+    with ThreadPoolExecutor(max_workers=spawn_concurrency) as executor:
+        future_to_scale_up = {
+            executor.submit(scale_up, pool, reason): pool["instance"]["flavor"]
+            for _ in range(nodes_to_create)
+        }
+
+        for future in concurrent.futures.as_completed(future_to_scale_up):
+            try:
+                future.result()
+                created += 1
+            except Exception as e:
+                errors.append(e)
+
+        executor.shutdown(wait=True)
+
+    if created:
+        record_creations(pool, created)
+        record_scale(pool, "up")
+        notify("scale_up", pool=get_pool_name(pool), count=created)
+    return created, errors
+
+
+def record_scale(pool: dict, direction: str):
+    with LAST_SCALED_LOCK:
+        LAST_SCALED.setdefault(get_pool_name(pool), {})[direction] = (
+            time.monotonic()
+        )
+
+
+def in_cooldown(cfg: dict, snapshot: Snapshot, pool: dict, direction: str) -> bool:
+    """Check if scaling a pool in a direction is held back by its cooldown.
+
+    A pool which scaled up is not scaled down again until its cooldown is over,
+    and the other way around, so that bursts don't cause it to flap.
+    """
+    cooldown = pool.get("cooldown_secs", cfg.get("cooldown_secs", 0))
+    opposite = "down" if direction == "up" else "up"
+    last = snapshot.last_scaled.get(get_pool_name(pool), {}).get(opposite)
+    return last is not None and snapshot.monotonic - last < cooldown
+
+
+def allocate_quota(pool: dict, nodes_to_create: int, allocated: dict) -> int:
+    """Allocate up to ``nodes_to_create`` servers of a pool in the compute quota.
+
+    The quota is shared by the pools of a cycle: the instances, cores and RAM
+    already allocated to the other pools in ``allocated`` count as used, and
+    the ones of the servers allocated here are added to it.  Limits which are
+    unlimited are ignored, and all of the servers are allocated if the limits
+    could not be retrieved.
+    """
+    try:
+        with openstack_errors():
+            with timed("openstack", "get_compute_limits"):
+                limits = CLOUD.get_compute_limits()
+            with timed("openstack", "get_flavor"):
+                flavor = CLOUD.compute.get_flavor(
+                    FLAVORS[get_resolved_flavors(pool)[0]]
+                )
+    except OpenStackError as e:
+        app.logger.warning("Failed to retrieve compute limits: %s", e)
+        return nodes_to_create
+
+    resources = (
+        ("instances", limits.max_total_instances, limits.total_instances_used, 1),
+        ("cores", limits.max_total_cores, limits.total_cores_used, flavor.vcpus),
+        ("ram", limits.max_total_ram_size, limits.total_ram_used, flavor.ram),
+    )
+    for resource, limit, used, size in resources:
+        if limit is None or limit < 0 or not size:
+            continue
+        used += allocated.get(resource, 0)
+        nodes_to_create = min(nodes_to_create, max(limit - used, 0) // size)
+
+    for resource, _, _, size in resources:
+        allocated[resource] = allocated.get(resource, 0) + nodes_to_create * size
+    return nodes_to_create
 
-scheduler = APScheduler()
-scheduler.init_app(app)
-scheduler.start()
 
+def notify(event: str, **fields):
+    """Post an event to the configured notifications webhook, if any.
 
-@webhook.hook(event_type="workflow_job")
-def on_workflow_job(data):
-    org = data["organization"]["login"]
-    if org != CFG["github"]["org"]:
+    This is best-effort: failures are logged and never propagated.
+    """
+    url = (CFG.get("notifications") or {}).get("url")
+    if not url:
         return
 
-    labels = data["workflow_job"]["labels"]
+    details = ", ".join("%s=%s" % item for item in sorted(fields.items()))
+    payload = {
+        "event": event,
+        "dry_run": DRY_RUN,
+        "text": "github-actions-openstack: %s (%s)" % (event, details),
+        **fields,
+    }
 
-    if data["action"] == "queued":
-        for pool in CFG["pools"]:
-            if pool["runner"]["label"] in labels:
-                scale_up(pool)
-                return
+    try:
+        requests.post(url, json=payload, timeout=5).raise_for_status()
+    except requests.RequestException as e:
+        app.logger.warning("Failed to send %s notification: %s", event, e)
 
-    if data["action"] == "completed":
-        runner_name = data["workflow_job"]["runner_name"]
-        app.logger.info("Deleting runner %s", runner_name)
-        CLOUD.compute.delete_server(runner_name)
 
+@traced
+def scale_up(pool: dict, reason: str = "unknown"):
+    app.logger.info("Scaling up")
 
-@scheduler.task(
-    "interval",
-    id="maintain_min_ready",
-    seconds=30,
-    max_instances=1,
-    next_run_time=datetime.now(),
-)
-def maintain_min_ready():
-    for pool in CFG["pools"]:
-        maintain_min_ready_for_pool(pool)
+    if not is_cloud_available():
+        error = OpenStackError("cloud is unavailable, not creating a server")
+        error.category = "unavailable"
+        raise error
 
-    servers = [s for s in CLOUD.compute.servers() if s.name.startswith("gha-")]
-    runners = g.get_organization(CFG["github"]["org"]).get_runners()
+    if pool["instance"]["image"] not in IMAGES or not get_resolved_flavors(pool):
+        raise ConfigError(
+            "image or flavor of pool '%s' not found" % get_pool_name(pool)
+        )
 
-    # Clean-up servers that don't have runners linked to them anymore
-    runner_names = [runner.name for runner in runners]
-    for server in servers:
-        if server.name in runner_names:
-            continue
+    # NOTE: The start script is checked before registering the runner, so
+    #       that a broken one doesn't leave runners without servers behind.
+    github_cfg = get_github_config(pool)
+    load_start_script(pool, get_registration(github_cfg))
 
-        app.logger.info("Deleting server %s", server.name)
-        CLOUD.compute.delete_server(server)
+    name = generate_name(pool)
+    set_span_attributes(pool=get_pool_name(pool), name=name)
+    fields = {"pool": get_pool_name(pool), "server": name}
+    if get_registration(github_cfg) == "token":
+        cloud_init = generate_cloud_config_with_jitconfig(
+            pool,
+            name,
+            "",
+            get_config_args(
+                pool, name, generate_registration_token(github_cfg, reason)
+            ),
+        )
+    else:
+        jitconfig = generate_jitconfig_for_organization(
+            github_cfg,
+            name,
+            get_runner_group_id(pool),
+            get_runner_labels(pool),
+            reason,
+        )
+        cloud_init = generate_cloud_config_with_jitconfig(pool, name, jitconfig)
 
-    # Clean-up runners that don't have servers linked to them anymore
-    server_names = [server.name for server in servers]
-    for runner in runners:
-        if runner.name in server_names:
-            continue
+    kwargs = {
+        "name": name,
+        "image": IMAGES[pool["instance"]["image"]],
+        "key_name": pool["instance"].get("key_name"),
+        "userdata": cloud_init,
+        "config_drive": pool["instance"].get("config_drive", False),
+        "description": get_server_description(pool, name),
+        "meta": {
+            "gha-managed-by": get_deployment_id(CFG),
+            "gha-github": get_github_name(pool),
+            "gha-runner-name": name,
+            "gha-pool": get_pool_name(pool),
+            "gha-labels": ",".join(get_runner_labels(pool))[:MAX_DESCRIPTION_LENGTH],
+            "gha-created": datetime.now(timezone.utc).isoformat(),
+        },
+        **pool["instance"].get("extra_server_fields", {}),
+    }
 
-        app.logger.info("Deleting runner %s", runner.name)
-        g.get_organization(CFG["github"]["org"]).remove_self_hosted_runner(runner)
+    if DRY_RUN:
+        app.logger.info(
+            "[dry-run] Would create server %s with image %s and flavor %s",
+            name,
+            kwargs["image"],
+            FLAVORS[get_resolved_flavors(pool)[0]],
+        )
+        audit("create_server", "dry_run", reason, **fields)
+        return
 
+    record_registration(pool, name)
 
-def maintain_min_ready_for_pool(pool: dict):
-    runners = get_runners_by_label(CFG["github"]["org"], pool["runner"]["label"])
-    idle_runners = [
-        runner
-        for runner in runners
-        # NOTE(mnaser): Once scale_up ensures that the runner is ready, we
-        #               should be able to add check here for online runners
-        if runner.busy is False
-    ]
+    try:
+        volumes = create_volumes(pool, name, reason)
+    except OpenStackError:
+        discard_registration(pool, name)
+        raise
+    if volumes:
+        kwargs["volumes"] = [volume.id for volume in volumes]
 
-    app.logger.info(
-        "%s: Found %s runners, %s idle runners, min_ready=%s",
-        pool["runner"]["label"],
-        len(runners),
-        len(idle_runners),
-        pool["min_ready"],
+    try:
+        ports = create_ports(pool, name, reason)
+    except OpenStackError:
+        with contextlib.suppress(Error):
+            delete_volumes(volumes, "create_failed")
+        discard_registration(pool, name)
+        raise
+    kwargs.update(get_network_kwargs(pool, ports))
+    if ports:
+        kwargs["meta"]["gha-ports"] = ",".join(port.id for port in ports)
+
+    # NOTE: Flavors are tried in order, moving on to the next one only when
+    #       the cloud has no capacity left for the current one.
+    flavors = get_resolved_flavors(pool)
+    for idx, flavor in enumerate(flavors):
+        try:
+            server = create_server(pool, {**kwargs, "flavor": FLAVORS[flavor]})
+            break
+        except OpenStackError as e:
+            if idx == len(flavors) - 1 or not is_capacity_error(e):
+                audit("create_server", "failure", reason, error=str(e), **fields)
+                # NOTE: A request which timed out may still create the server
+                #       with the volumes and ports, which are then cleaned up
+                #       along with it like those of any orphaned server.
+                if isinstance(e, CreateTimeoutError):
+                    raise
+                with contextlib.suppress(Error):
+                    delete_volumes(volumes, "create_failed")
+                with contextlib.suppress(Error):
+                    delete_ports([port.id for port in ports], "create_failed")
+                discard_registration(pool, name)
+                raise
+
+            app.logger.warning(
+                "No capacity for flavor %s, trying %s for %s: %s",
+                flavor,
+                flavors[idx + 1],
+                name,
+                e,
+            )
+            # NOTE: The server which failed to schedule is left in ERROR, and
+            #       is deleted even with 'keep_failed_instances_secs', since it
+            #       shares its runner name with the server of the next flavor
+            #       and would be mistaken for it.
+            with OPENSTACK_SEMAPHORE:
+                with contextlib.suppress(openstack.exceptions.SDKException):
+                    CLOUD.delete_server(name)
+                    audit("delete_server", "success", "no_capacity", **fields)
+
+    try:
+        tag_server(server, [get_managed_tag(CFG), *pool["instance"].get("tags", [])])
+    except OpenStackError as e:
+        app.logger.error("Failed to tag server %s, deleting it: %s", server.name, e)
+        audit("create_server", "failure", reason, error=str(e), **fields)
+        with contextlib.suppress(Error):
+            delete_node(server, reason="tag_failed")
+        discard_registration(pool, name, "tag_failed")
+        raise
+
+    record_instance(pool, server)
+    with INSTANCES_LOCK:
+        REGISTRATIONS.pop(name, None)
+    app.logger.info("Created server %s with flavor %s", server.name, flavor)
+    audit(
+        "create_server", "success", reason, server_id=server.id, flavor=flavor, **fields
     )
 
-    nodes_to_create = pool["min_ready"] - len(idle_runners)
-    if nodes_to_create > 0:
-        app.logger.info("Scaling up %s nodes", nodes_to_create)
+    # NOTE(mnaser): We should ideally wait for the runner to be ready inside
+    #               GHA, if not we drop out.
 
-        with ThreadPoolExecutor(max_workers=4) as executor:
-            future_to_scale_up = {
-                executor.submit(scale_up, pool): pool["instance"]["flavor"]
-                for _ in range(nodes_to_create)
-            }
 
-            for future in concurrent.futures.as_completed(future_to_scale_up):
-                future.result()
+def create_server(pool: dict, kwargs: dict):
+    """Create a server and wait for it to become active.
 
-            executor.shutdown(wait=True)
+    Like when deleting servers, the semaphore is only held for each call to
+    OpenStack while waiting, so that servers which take a while to boot don't
+    hold up the other calls.
+    """
+    with openstack_errors(), timed("openstack", "create_server"):
+        server = request_server(pool, kwargs)
+        deadline = time.monotonic() + 300
+        while server.status == "BUILD" and time.monotonic() < deadline:
+            time.sleep(2)
+            with OPENSTACK_SEMAPHORE:
+                server = CLOUD.get_server(server.id) or server
+        # NOTE: This returns right away for a server which is done building,
+        #       once its addresses are set up, and times out otherwise.
+        with OPENSTACK_SEMAPHORE:
+            return CLOUD.wait_for_server(
+                server, timeout=max(deadline - time.monotonic(), 1)
+            )
 
 
-def scale_up(pool: dict):
-    app.logger.info("Scaling up")
+def request_server(pool: dict, kwargs: dict):
+    scheduler_hints = dict(pool["instance"].get("scheduler_hints") or {})
+    server_group = pool["instance"].get("server_group")
+    if server_group is None:
+        return send_create_request(**kwargs, scheduler_hints=scheduler_hints or None)
 
-    name = generate_name()
-    jitconfig = generate_jitconfig_for_organization(
-        CFG["github"]["org"],
-        name,
-        pool["runner"]["group"],
-        [pool["runner"]["label"]],
-    )
-    cloud_init = generate_cloud_config_with_jitconfig(jitconfig)
+    try:
+        return send_create_request(
+            **kwargs,
+            scheduler_hints={
+                **scheduler_hints,
+                "group": SERVER_GROUPS[server_group],
+            },
+        )
+    except openstack.exceptions.HttpException as e:
+        if "too many servers in group" not in str(e).lower():
+            raise
 
-    server = CLOUD.create_server(
-        name=name,
-        image=pool["instance"]["image"],
-        flavor=pool["instance"]["flavor"],
-        network=pool["instance"]["network"],
-        key_name=pool["instance"].get("key_name"),
-        userdata=cloud_init,
-        wait=True,
-        timeout=300,
-    )
+        app.logger.warning(
+            "Server group %s is full, creating %s outside of it",
+            server_group,
+            kwargs["name"],
+        )
+        return send_create_request(**kwargs, scheduler_hints=scheduler_hints or None)
 
-    # TODO: If we fail here, we should delete the runner token
 
-    app.logger.info("Created server %s", server.name)
+def send_create_request(**kwargs):
+    """Send the request to create a server, giving up after a while.
+
+    With ``create_timeout_secs`` set, a request that Nova doesn't answer in
+    time fails with a timeout, separately from waiting for the server to become
+    active.  The request is left to finish in the background, still holding
+    its slot of the OpenStack semaphore: a server it still creates has its
+    runner, so later cycles count it like any other.
+    """
+    timeout = CFG["openstack"].get("create_timeout_secs")
+    if timeout is None:
+        with OPENSTACK_SEMAPHORE:
+            return CLOUD.create_server(**kwargs)
+
+    # NOTE: The semaphore is kept at hand since a reload of the configuration
+    #       may replace it before the request finishes.
+    semaphore = OPENSTACK_SEMAPHORE
+
+    def create():
+        try:
+            return CLOUD.create_server(**kwargs)
+        finally:
+            semaphore.release()
+
+    semaphore.acquire()
+    executor = ThreadPoolExecutor(max_workers=1)
+    future = executor.submit(create)
+    executor.shutdown(wait=False)
+    try:
+        return future.result(timeout=timeout)
+    except concurrent.futures.TimeoutError:
+        raise CreateTimeoutError(
+            "request to create server %s timed out after %s seconds"
+            % (kwargs["name"], timeout)
+        ) from None
+
+
+def tag_server(server, tags: list):
+    """Replace the tags of a server, which needs compute microversion 2.26."""
+    with OPENSTACK_SEMAPHORE, openstack_errors(), timed("openstack", "set_tags"):
+        response = CLOUD.compute.put(
+            "/servers/%s/tags" % server.id,
+            json={"tags": list(dict.fromkeys(tags))},
+            microversion="2.26",
+        )
+        openstack.exceptions.raise_from_response(response)
+
+
+def add_server_tag(server, tag: str):
+    with OPENSTACK_SEMAPHORE, openstack_errors(), timed("openstack", "add_tag"):
+        response = CLOUD.compute.put(
+            "/servers/%s/tags/%s" % (server.id, tag), microversion="2.26"
+        )
+        openstack.exceptions.raise_from_response(response)
 
-    # NOTE(mnaser): We should ideally wait for the runner to be ready inside
-    #               GHA, if not we drop out.
 
+def create_volumes(pool: dict, name: str, reason: str) -> list:
+    """Create the data volumes of a server, tagged with its runner name."""
+    volumes = []
+    for idx, volume_cfg in enumerate(pool["instance"].get("volumes", [])):
+        delete_on_termination = volume_cfg.get("delete_on_termination", True)
+        try:
+            with OPENSTACK_SEMAPHORE, openstack_errors():
+                with timed("openstack", "create_volume"):
+                    volume = CLOUD.create_volume(
+                        volume_cfg["size"],
+                        wait=True,
+                        name="%s-%d" % (name, idx),
+                        volume_type=volume_cfg.get("type"),
+                        metadata={
+                            "gha-managed-by": get_deployment_id(CFG),
+                            "gha-runner-name": name,
+                            "gha-delete-on-termination": str(
+                                delete_on_termination
+                            ).lower(),
+                        },
+                    )
+        except OpenStackError as e:
+            audit("create_volume", "failure", reason, server=name, error=str(e))
+            with contextlib.suppress(Error):
+                delete_volumes(volumes, "create_failed")
+            raise
+        audit(
+            "create_volume",
+            "success",
+            reason,
+            server=name,
+            volume=volume.name,
+            volume_id=volume.id,
+        )
+        volumes.append(volume)
+    return volumes
+
+
+def create_ports(pool: dict, name: str, reason: str) -> list:
+    """Create the ports of a server for the networks with a ``subnet``.
+
+    Nova can only pick a fixed IP on a network, so pinning a server to a
+    subnet takes creating its port first.  The ports are named after the
+    server and deleted along with it.
+    """
+    ports = []
+    for network in get_networks(pool):
+        if not isinstance(network, dict) or "subnet" not in network:
+            continue
+
+        try:
+            with OPENSTACK_SEMAPHORE, openstack_errors():
+                network_id = get_resource_id(
+                    CLOUD.get_network, "network", network["network"]
+                )
+                fixed_ip = {
+                    "subnet_id": get_resource_id(
+                        CLOUD.get_subnet, "subnet", network["subnet"]
+                    )
+                }
+                if "fixed_ip" in network:
+                    fixed_ip["ip_address"] = network["fixed_ip"]
+                with timed("openstack", "create_port"):
+                    port = CLOUD.create_port(
+                        network_id,
+                        name=name,
+                        description=get_managed_tag(CFG),
+                        fixed_ips=[fixed_ip],
+                    )
+        except OpenStackError as e:
+            audit("create_port", "failure", reason, server=name, error=str(e))
+            with contextlib.suppress(Error):
+                delete_ports([port.id for port in ports], "create_failed")
+            raise
+        audit("create_port", "success", reason, server=name, port_id=port.id)
+        ports.append(port)
+    return ports
+
+
+def get_resource_id(get, kind: str, ref: str) -> str:
+    if is_uuid(ref):
+        return ref
+    resource = get(ref)
+    if resource is None:
+        raise OpenStackError("%s '%s' not found" % (kind, ref))
+    return resource.id
+
+
+def get_network_kwargs(pool: dict, ports: list) -> dict:
+    """Return how to attach a server to the networks of its pool.
+
+    Networks given by name are passed as is, while any subnet, fixed IP or port
+    takes describing every network as a NIC.
+    """
+    networks = get_networks(pool)
+    if all(isinstance(network, str) for network in networks):
+        return {"network": pool["instance"]["network"]}
+
+    nics = []
+    ports = iter(ports)
+    for network in networks:
+        if isinstance(network, str):
+            nics.append({"net-name": network})
+        elif "port" in network:
+            nics.append({"port-id": network["port"]})
+        elif "subnet" in network:
+            nics.append({"port-id": next(ports).id})
+        else:
+            nic = {"net-name": network["network"]}
+            if "fixed_ip" in network:
+                nic["fixed_ip"] = network["fixed_ip"]
+            nics.append(nic)
+    return {"nics": nics}
+
+
+def delete_ports(port_ids: list, reason: str):
+    for port_id in port_ids:
+        app.logger.info("Deleting port %s", port_id)
+        try:
+            with openstack_errors(), timed("openstack", "delete_port"):
+                CLOUD.delete_port(port_id)
+        except OpenStackError as e:
+            audit("delete_port", "failure", reason, error=str(e), port_id=port_id)
+            raise
+        audit("delete_port", "success", reason, port_id=port_id)
+
+
+def is_capacity_error(e: Exception) -> bool:
+    message = str(e).lower()
+    return "no valid host" in message or "insufficient" in message
 
-def generate_cloud_config_with_jitconfig(jitconfig: str):
+
+def get_flavors(pool: dict) -> list[str]:
+    flavor = pool["instance"]["flavor"]
+    return flavor if isinstance(flavor, list) else [flavor]
+
+
+def get_resolved_flavors(pool: dict) -> list[str]:
+    return [flavor for flavor in get_flavors(pool) if flavor in FLAVORS]
+
+
+def generate_cloud_config_with_jitconfig(
+    pool: dict, name: str, jitconfig: str, config_args: str = ""
+) -> str | bytes:
     cloud_config = {
+        "hostname": name,
         "write_files": [],
-        "runcmd": [
-            "/start.sh",
-        ],
+        "runcmd": [],
     }
 
-    with open("scripts/start.sh", "r", encoding="utf-8") as f:
+    domain = pool["instance"].get("domain")
+    if domain:
+        cloud_config["fqdn"] = name + "." + domain
+
+    # NOTE: The JIT config is substituted in the start script unquoted, while
+    #       the arguments of config.sh are quoted as a whole.  Only one of them
+    #       is set, depending on how the runner is registered.
+    if jitconfig and shlex.quote(jitconfig) != jitconfig:
+        raise CloudConfigError("JIT config of runner %s is not shell-safe" % name)
+
+    registration = "token" if config_args else "jit"
+    cloud_config["write_files"].append(
+        {
+            "path": "/start.sh",
+            "content": load_start_script(pool, registration)
+            .replace(JIT_CONFIG_PLACEHOLDER, jitconfig)
+            .replace(CONFIG_ARGS_PLACEHOLDER, shlex.quote(config_args)),
+            "permissions": "0755",
+        }
+    )
+
+    # NOTE: The proxy settings go in a profile script so that they apply both
+    #       to the start script and to the runner's login shell.
+    proxy_env = generate_proxy_env(pool["instance"].get("proxy") or {})
+    if proxy_env:
         cloud_config["write_files"].append(
             {
-                "path": "/start.sh",
-                "content": f.read().replace("___JIT_CONFIG___", jitconfig),
-                "permissions": "0755",
+                "path": "/etc/profile.d/gha-proxy.sh",
+                "content": proxy_env,
+                "permissions": "0644",
             }
         )
 
-    return "#cloud-config\n" + yaml.dump(cloud_config)
+    # NOTE: Extra commands run before the start script, so that anything they
+    #       set up is in place by the time the runner starts.
+    cloud_config["write_files"].extend(pool["instance"].get("extra_write_files", []))
+    cloud_config["runcmd"].extend(pool["instance"].get("extra_runcmd", []))
+    if pool["instance"].get("debug_boot", False):
+        # NOTE: The runner keeps running in the background, so the output is
+        #       sent through a process substitution which isn't waited for.
+        cloud_config["runcmd"].append(
+            ["bash", "-c", "exec > >(tee -a %s) 2>&1; bash -x /start.sh" % BOOT_LOG]
+        )
+    else:
+        cloud_config["runcmd"].append("/start.sh")
+    if "runcmd" in pool["instance"]:
+        cloud_config["runcmd"] = list(pool["instance"]["runcmd"])
+
+    user_data = "#cloud-config\n" + yaml.dump(cloud_config)
+    try:
+        rendered = yaml.safe_load(user_data)
+    except yaml.YAMLError as e:
+        raise CloudConfigError("user data is not valid YAML: %s" % e) from e
+    if rendered != cloud_config:
+        raise CloudConfigError("user data does not render the cloud config")
+
+    # NOTE: cloud-init recognizes gzipped user data by its magic number, and
+    #       the timestamp is left out so that the same config compresses the
+    #       same way.
+    data = user_data.encode("utf-8")
+    compress = pool["instance"].get("gzip_user_data", "auto")
+    if compress == "auto":
+        compress = len(base64.b64encode(data)) > MAX_USER_DATA_SIZE
+    if compress:
+        data = gzip.compress(data, mtime=0)
+
+    size = len(base64.b64encode(data))
+    if size > MAX_USER_DATA_SIZE:
+        raise CloudConfigError(
+            "user data is %d bytes once base64 encoded%s, which is over the "
+            "limit of %d bytes, consider gzipping large files in "
+            "'extra_write_files'"
+            % (size, " and gzipped" if compress else "", MAX_USER_DATA_SIZE)
+        )
+
+    return data if compress else user_data
+
+
+def generate_proxy_env(proxy: dict) -> str:
+    lines = []
+    for key in ("http_proxy", "https_proxy", "no_proxy"):
+        if proxy.get(key):
+            value = shlex.quote(proxy[key])
+            lines.append("export %s=%s" % (key, value))
+            lines.append("export %s=%s" % (key.upper(), value))
+    return "\n".join(lines) + "\n" if lines else ""
 
 
 def get_runners(self):
-    return github.PaginatedList.PaginatedList(
-        github.SelfHostedActionsRunner.SelfHostedActionsRunner,
-        self._requester,
-        self.url + "/actions/runners",
-        None,
-        list_item="runners",
-    )
+    """List all of the runners of the organization.
+
+    Each page is retried a few times on server or connection errors, and the
+    listing fails as a whole if a page can't be fetched, so that nothing acts
+    on a partial list of runners.
+    """
+    runners = {}
+    page = 1
+    while True:
+        for attempt in range(3):
+            try:
+                with timed("github", "list_runners"):
+                    headers, data = self._requester.requestJsonAndCheck(
+                        "GET",
+                        self.url + "/actions/runners",
+                        parameters={"per_page": 100, "page": page},
+                    )
+                break
+            except (github.GithubException, requests.RequestException) as e:
+                status = getattr(e, "status", None)
+                if attempt == 2 or (status is not None and status < 500):
+                    raise IncompleteInventoryError(
+                        "failed to list page %d of the runners: %s" % (page, e),
+                        status,
+                    ) from e
+                time.sleep(2**attempt)
+
+        # NOTE: Runners may move between pages while they are listed, so they
+        #       are deduplicated by id.
+        for item in data["runners"]:
+            runners[item["id"]] = (
+                github.SelfHostedActionsRunner.SelfHostedActionsRunner(
+                    self._requester, headers, item, completed=True
+                )
+            )
+        if len(data["runners"]) < 100:
+            return list(runners.values())
+        page += 1
 
 
 github.Organization.Organization.get_runners = get_runners
 
 
+def get_self_hosted_runner(self, runner_id: int):
+    headers, data = self._requester.requestJsonAndCheck(
+        "GET", self.url + "/actions/runners/" + str(runner_id)
+    )
+    return github.SelfHostedActionsRunner.SelfHostedActionsRunner(
+        self._requester, headers, data, completed=True
+    )
+
+
+github.Organization.Organization.get_self_hosted_runner = get_self_hosted_runner
+
+
 def remove_self_hosted_runner(self, runner):
     assert isinstance(
         runner, github.SelfHostedActionsRunner.SelfHostedActionsRunner
@@ -193,52 +4314,687 @@ def remove_self_hosted_runner(self, runner):
     if isinstance(runner, github.SelfHostedActionsRunner.SelfHostedActionsRunner):
         runner = runner.id
 
+    # NOTE: A runner which is already gone counts as removed, since GitHub
+    #       removes ephemeral runners by itself once they ran their job.
     status, _, _ = self._requester.requestJson(
         "DELETE", self.url + "/actions/runners/" + str(runner)
     )
-    return status == 204
+    return status in (204, 404)
 
 
 github.Organization.Organization.remove_self_hosted_runner = remove_self_hosted_runner
 
 
-def get_runners_by_label(
-    org: str, label: str
-) -> list[github.SelfHostedActionsRunner.SelfHostedActionsRunner]:
-    runners = g.get_organization(CFG["github"]["org"]).get_runners()
+def get_runner_groups(self):
+    groups = []
+    page = 1
+    while True:
+        _, data = self._requester.requestJsonAndCheck(
+            "GET",
+            self.url + "/actions/runner-groups",
+            parameters={"per_page": 100, "page": page},
+        )
+        groups.extend(data["runner_groups"])
+        if len(data["runner_groups"]) < 100:
+            return groups
+        page += 1
+
+
+github.Organization.Organization.get_runner_groups = get_runner_groups
+
+
+# NOTE: Runner group names are resolved at startup (and on reload), this maps
+#       them to the numeric ids that GitHub expects when generating JIT configs.
+RUNNER_GROUPS: dict[tuple[str, str], int] = {}
+
+
+def resolve_runner_groups(
+    cfg: dict, githubs: dict[str, github.Github]
+) -> dict[tuple[str, str], int]:
+    runner_groups = {}
+    for github_name, github_cfg in get_github_configs(cfg).items():
+        names = {
+            pool["runner"]["group"]
+            for pool in cfg["pools"]
+            if pool["runner"].get("github", "default") == github_name
+            and isinstance(pool["runner"]["group"], str)
+        }
+        if not names:
+            continue
+
+        org = github_cfg["org"]
+        groups = {
+            group["name"]: group["id"]
+            for group in githubs[github_name].get_organization(org).get_runner_groups()
+        }
+
+        for name in names:
+            if name not in groups:
+                raise ConfigError(
+                    "runner group '%s' not found in organization '%s'" % (name, org)
+                )
+            runner_groups[(github_name, name)] = groups[name]
+    return runner_groups
+
+
+# NOTE: Server group names are resolved (or created) at startup (and on
+#       reload), this maps them to the ids used as scheduler hints.
+SERVER_GROUPS: dict[str, str] = {}
+
+
+def resolve_server_groups(cfg: dict, cloud) -> dict[str, str]:
+    server_groups = {}
+    for pool in cfg["pools"]:
+        name = pool["instance"].get("server_group")
+        if name is None or name in server_groups:
+            continue
+
+        group = cloud.get_server_group(name)
+        if group is None and cfg.get("dry_run", False):
+            app.logger.info("[dry-run] Would create server group %s", name)
+            continue
+        if group is None:
+            app.logger.info("Creating server group %s", name)
+            group = cloud.create_server_group(name, ["anti-affinity"])
+        server_groups[name] = group.id
+    return server_groups
+
+
+def ensure_keypairs(cfg: dict, cloud):
+    """Make sure the keypairs used by the pools exist.
+
+    A missing keypair is imported from the pool's ``public_key`` if it has
+    one, otherwise the controller refuses to start.
+    """
+    seen = set()
+    for pool in cfg["pools"]:
+        name = pool["instance"].get("key_name")
+        if name is None or name in seen:
+            continue
+        seen.add(name)
+
+        if cloud.get_keypair(name) is not None:
+            continue
+
+        public_key = pool["instance"].get("public_key")
+        if public_key is None:
+            raise ConfigError(
+                "keypair '%s' not found and no 'public_key' to create it" % name
+            )
+        if cfg.get("dry_run", False):
+            app.logger.info("[dry-run] Would create keypair %s", name)
+            continue
+
+        app.logger.info("Creating keypair %s", name)
+        cloud.create_keypair(name, public_key=public_key)
+
+
+# NOTE: Image and flavor names are resolved at startup (and on reload), this
+#       maps them to their ids so they don't have to be looked up on every boot.
+IMAGES: dict[str, str] = {}
+FLAVORS: dict[str, str] = {}
+
+# NOTE: Names of the images and flavors given by id, for computed labels.
+INSTANCE_REF_NAMES: dict[str, str] = {}
+
+
+def resolve_instance_refs(cfg: dict, key: str, get) -> dict[str, str]:
+    """Resolve the images or flavors of the pools to their ids.
+
+    The ones which cannot be found are left out, so that only the pools using
+    them are held back (see `check_instance_refs`).
+    """
+    refs = {}
+    for pool in cfg["pools"]:
+        values = pool["instance"][key]
+        for ref in values if isinstance(values, list) else [values]:
+            if ref in refs:
+                continue
+
+            if is_uuid(ref):
+                refs[ref] = ref
+                continue
+
+            resource = get(ref)
+            if resource is None:
+                app.logger.error("%s '%s' not found", key, ref)
+                continue
+            refs[ref] = resource.id
+    return refs
+
+
+def get_instance_ref_name(ref: str, get) -> str:
+    """Return the name of an image or flavor, looking it up if given by id."""
+    if not is_uuid(ref):
+        return ref
+
+    if ref not in INSTANCE_REF_NAMES:
+        with openstack_errors():
+            resource = get(ref)
+        if resource is None:
+            return ref
+        INSTANCE_REF_NAMES[ref] = resource.name
+    return INSTANCE_REF_NAMES[ref]
+
+
+def check_instance_refs(pool: dict) -> str | None:
+    """Check that the image and flavors of a pool can still be found.
+
+    Images and flavors whose id is gone are resolved again, in case they were
+    replaced by another one with the same name.  Ids given in the
+    configuration are looked up as well, so that a deleted one is noticed.  A
+    description of what is missing is returned, if anything.
+    """
+    with openstack_errors():
+        image = pool["instance"]["image"]
+        if not refresh_instance_ref(IMAGES, image, CLOUD.get_image):
+            return "image '%s' not found" % image
+
+        flavors = get_flavors(pool)
+        if not any(
+            [refresh_instance_ref(FLAVORS, f, CLOUD.get_flavor) for f in flavors]
+        ):
+            return "flavor '%s' not found" % "', '".join(flavors)
+    return None
+
+
+def refresh_instance_ref(refs: dict[str, str], ref: str, get) -> bool:
+    if ref in refs and get(refs[ref]) is not None:
+        return True
+
+    resource = get(ref)
+    if resource is None:
+        refs.pop(ref, None)
+        return False
+
+    refs[ref] = resource.id
+    return True
+
+
+def get_runner_group_id(pool: dict) -> int:
+    group = pool["runner"]["group"]
+    if isinstance(group, int):
+        return group
+    return RUNNER_GROUPS[(get_github_name(pool), group)]
+
+
+def classify_runner(runner, seen_online: set | None = None) -> str:
+    """Classify a runner as idle, busy, booting or offline.
+
+    Only online runners which are not busy are actually available to pick up
+    jobs.  Runners which are offline and have never been seen online are
+    still booting, while the ones which have been online before are gone and
+    should be cleaned up.
+    """
+    if runner.busy:
+        return "busy"
+    if runner.status == "online":
+        return "idle"
+    if runner_key(runner) in (SEEN_ONLINE if seen_online is None else seen_online):
+        return "offline"
+    return "booting"
+
+
+def runner_labels(runner) -> list[str]:
     return [
-        runner
-        for runner in runners
-        if label in [label["name"] for label in runner.labels()]
+        normalize_label(label["name"])
+        for label in runner.labels() or []
+        if isinstance(label, dict) and label.get("name")
     ]
 
 
+def get_server_description(pool: dict, name: str) -> str:
+    """Return the description of a server, from the pool's ``description``.
+
+    It can refer to the ``{pool}`` and ``{labels}`` of the server, and to its
+    runner's ``{name}``.
+    """
+    description = pool["instance"].get("description", DEFAULT_DESCRIPTION)
+    return description.format(
+        pool=get_pool_name(pool),
+        labels=", ".join(get_runner_labels(pool)),
+        name=name,
+    )[:MAX_DESCRIPTION_LENGTH]
+
+
+def get_runner_labels(pool: dict) -> list[str]:
+    """Return the labels to register the runners of a pool with.
+
+    These are the label of the pool followed by the ``default_labels`` and its
+    computed labels, with duplicates left out.
+    """
+    labels = {}
+    for label in [
+        pool["runner"]["label"],
+        *CFG.get("default_labels", []),
+        *get_computed_labels(pool),
+    ]:
+        labels.setdefault(normalize_label(label), label.strip())
+    return list(labels.values())
+
+
+def get_computed_labels(pool: dict) -> list[str]:
+    """Return the labels computed from the servers of a pool.
+
+    These are its availability zone, flavor and image, prefixed as listed in
+    ``COMPUTED_LABELS``.  Flavors and images given by id are looked up for
+    their name.
+    """
+    labels = []
+    for key in pool["runner"].get("computed_labels", []):
+        if key == "availability_zone":
+            value = pool["instance"]["extra_server_fields"]["availability_zone"]
+        elif key == "flavor":
+            value = get_instance_ref_name(get_flavors(pool)[0], CLOUD.get_flavor)
+        else:
+            value = get_instance_ref_name(pool["instance"]["image"], CLOUD.get_image)
+        labels.append("%s-%s" % (COMPUTED_LABELS[key], value))
+    return labels
+
+
+@traced
 def generate_jitconfig_for_organization(
-    org: str, name: str, runner_group_id: int, labels: list[str]
+    github_cfg: dict,
+    name: str,
+    runner_group_id: int,
+    labels: list[str],
+    reason: str = "unknown",
 ):
-    response = requests.post(
-        "https://api.github.com/orgs/" + org + "/actions/runners/generate-jitconfig",
-        timeout=5,
-        headers={
-            "Accept": "application/vnd.github+json",
-            "Authorization": "Bearer " + CFG["github"]["token"],
-            "X-GitHub-Api-Version": "2022-11-28",
-        },
-        json={
-            "name": name,
-            "runner_group_id": runner_group_id,
-            "labels": labels,
-        },
-    )
-    response.raise_for_status()
+    fields = {"runner": name, "org": github_cfg["org"], "labels": labels}
+    if DRY_RUN:
+        app.logger.info("[dry-run] Would generate JIT config for runner %s", name)
+        audit("generate_jitconfig", "dry_run", reason, **fields)
+        return "dry-run"
+
+    # NOTE: Server errors and connection failures are retried with a short
+    #       backoff, anything else (e.g. an invalid runner group) is not.
+    for attempt in range(3):
+        try:
+            with timed("github", "generate_jitconfig"):
+                response = github_request(
+                    github_cfg,
+                    "POST",
+                    "/orgs/%s/actions/runners/generate-jitconfig" % github_cfg["org"],
+                    json={
+                        "name": name,
+                        "runner_group_id": runner_group_id,
+                        "labels": labels,
+                    },
+                )
+            break
+        except GitHubError as e:
+            if attempt == 2 or (e.status is not None and e.category != "server"):
+                audit("generate_jitconfig", "failure", reason, error=str(e), **fields)
+                raise
+
+            app.logger.warning(
+                "Failed to generate JIT config for runner %s, retrying: %s", name, e
+            )
+            time.sleep(2**attempt)
+
+    audit("generate_jitconfig", "success", reason, **fields)
     return response.json().get("encoded_jit_config")
 
 
-def generate_name():
+def generate_registration_token(github_cfg: dict, reason: str = "unknown") -> str:
+    """Create a registration token, for GitHub servers without JIT configs."""
+    fields = {"org": github_cfg["org"]}
+    if DRY_RUN:
+        app.logger.info("[dry-run] Would create a registration token")
+        audit("generate_registration_token", "dry_run", reason, **fields)
+        return "dry-run"
+
+    try:
+        with timed("github", "generate_registration_token"):
+            response = github_request(
+                github_cfg,
+                "POST",
+                "/orgs/%s/actions/runners/registration-token" % github_cfg["org"],
+            )
+    except GitHubError as e:
+        audit("generate_registration_token", "failure", reason, error=str(e), **fields)
+        raise
+
+    audit("generate_registration_token", "success", reason, **fields)
+    return response.json()["token"]
+
+
+def get_config_args(pool: dict, name: str, token: str) -> str:
+    """Return the arguments to register a runner with config.sh.
+
+    Runners are registered to run a single job, like with a JIT config.
+    """
+    github_cfg = get_github_config(pool)
+    args = [
+        "--unattended",
+        "--ephemeral",
+        "--disableupdate",
+        "--url",
+        "%s/%s" % (get_github_url(github_cfg), github_cfg["org"]),
+        "--token",
+        token,
+        "--name",
+        name,
+        "--runnergroup",
+        pool["runner"]["group"],
+        "--labels",
+        ",".join(get_runner_labels(pool)),
+    ]
+    return " ".join(shlex.quote(arg) for arg in args)
+
+
+def github_request(
+    github_cfg: dict, method: str, path: str, **kwargs
+) -> requests.Response:
+    """Send a request to the GitHub API, waiting out any rate limits.
+
+    If GitHub responds with a primary or secondary rate limit, the request is
+    retried after the delay it advertised, unless that delay is longer than
+    the configured maximum.
+    """
+    max_retry_wait = github_cfg.get("max_retry_wait", 60)
+    for attempt in range(5):
+        with github_errors():
+            response = requests.request(
+                method,
+                get_github_api_url(github_cfg) + path,
+                timeout=5,
+                headers={
+                    "Accept": "application/vnd.github+json",
+                    "Authorization": "Bearer " + github_cfg["token"],
+                    "X-GitHub-Api-Version": "2022-11-28",
+                },
+                **kwargs,
+            )
+
+        github_name = get_github_name_for(github_cfg)
+        if github_name and "X-RateLimit-Remaining" in response.headers:
+            record_rate_limit(
+                github_name,
+                int(response.headers["X-RateLimit-Remaining"]),
+                float(response.headers.get("X-RateLimit-Reset", 0)),
+            )
+
+        delay = get_rate_limit_delay(response)
+        if delay is None or delay > max_retry_wait or attempt == 4:
+            break
+
+        app.logger.warning(
+            "Rate limited by GitHub, retrying %s %s in %s seconds",
+            method,
+            path,
+            delay,
+        )
+        time.sleep(delay)
+
+    if not response.ok:
+        raise GitHubError(
+            "%s %s failed: %s %s"
+            % (method, path, response.status_code, response.text),
+            response.status_code,
+        )
+    return response
+
+
+def get_rate_limit_delay(response: requests.Response) -> float | None:
+    if response.status_code not in (403, 429):
+        return None
+
+    if "Retry-After" in response.headers:
+        return float(response.headers["Retry-After"])
+
+    if response.headers.get("X-RateLimit-Remaining") == "0":
+        reset = float(response.headers.get("X-RateLimit-Reset", 0))
+        return max(reset - time.time(), 0)
+
+    return None
+
+
+def generate_name(pool: dict):
     letters = string.ascii_lowercase
-    suffix = "".join(random.choice(letters) for i in range(5))
-    return "gha-" + suffix
+    suffix = "".join(random.choice(letters) for i in range(NAME_SUFFIX_LENGTH))
+    return get_name_prefix(CFG, pool) + get_name_discriminator(CFG) + suffix
+
+
+def get_name_discriminator(cfg: dict) -> str:
+    """Return what tells the names of this cloud apart from other clouds'.
+
+    Generated names have the ``name_discriminator`` and a dash between their
+    prefix and their suffix, if it is set.
+    """
+    name_discriminator = cfg["openstack"].get("name_discriminator")
+    return name_discriminator + "-" if name_discriminator else ""
+
+
+def is_generated_name(cfg: dict, name: str) -> bool:
+    """Check if a name has the shape of the ones given to servers and runners.
+
+    That is one of the name prefixes followed by a suffix of lowercase letters
+    of the same length, so that lookalike names from other tools (such as
+    ``gha-company-prod-xyz``) are never taken for ours.  With a name
+    discriminator, names of other clouds are never taken for ours either,
+    while names generated before it was set still are.
+    """
+    prefixes = {cfg.get("resource_prefix", "gha-")}
+    prefixes.update(get_name_prefix(cfg, pool) for pool in cfg["pools"])
+    pattern = "(?:%s)(?:%s)?[a-z]{%d}" % (
+        "|".join(map(re.escape, sorted(prefixes))),
+        re.escape(get_name_discriminator(cfg)),
+        NAME_SUFFIX_LENGTH,
+    )
+    return re.fullmatch(pattern, name) is not None
+
+
+def get_name_prefix(cfg: dict, pool: dict) -> str:
+    return pool["runner"].get("name_prefix", cfg.get("resource_prefix", "gha-"))
+
+
+def startup():
+    global RUNNER_GROUPS, SERVER_GROUPS, IMAGES, FLAVORS
+
+    RUNNER_GROUPS = resolve_runner_groups(CFG, GITHUBS)
+    SERVER_GROUPS = resolve_server_groups(CFG, CLOUD)
+    ensure_keypairs(CFG, CLOUD)
+    IMAGES = resolve_instance_refs(CFG, "image", CLOUD.get_image)
+    FLAVORS = resolve_instance_refs(CFG, "flavor", CLOUD.get_flavor)
+    load_state()
+    reap_registrations()
+
+    # NOTE: uWSGI uses SIGHUP to reload its workers, so the configuration file
+    #       is only watched for changes when running under it.  Draining runs
+    #       from its shutdown hook or from the SIGTERM handler rather than from
+    #       the exit handlers, by when no new threads can be started anymore.
+    if "uwsgi" in sys.modules:
+        sys.modules["uwsgi"].atexit = drain
+    else:
+        signal.signal(signal.SIGHUP, lambda *_: RELOAD_REQUESTED.set())
+        signal.signal(signal.SIGTERM, lambda *_: (drain(), sys.exit(0)))
+
+    scheduler.start()
+
+
+def preflight(cfg: dict) -> bool:
+    """Check the configuration against GitHub and OpenStack, printing a report.
+
+    The same lookups as at startup are done, but nothing is created: missing
+    keypairs and server groups which would be created are only logged.  The
+    OpenStack checks are skipped if connecting to it fails.
+    """
+    read_only = {**cfg, "dry_run": True}
+    results = []
+
+    def check(name: str, func) -> bool:
+        try:
+            func()
+        except Exception as e:
+            results.append((name, str(e) or type(e).__name__))
+            print("FAIL %s: %s" % (name, results[-1][1]))
+            return False
+        results.append((name, None))
+        print("PASS %s" % name)
+        return True
+
+    def connect():
+        global CLOUD
+        CLOUD = connect_openstack(cfg)
+
+    def check_networks():
+        missing = set()
+        for pool in cfg["pools"]:
+            for network in get_networks(pool):
+                if isinstance(network, str):
+                    network = {"network": network}
+                for key, get in (
+                    ("network", CLOUD.get_network),
+                    ("subnet", CLOUD.get_subnet),
+                    ("port", CLOUD.get_port),
+                ):
+                    if key in network and get(network[key]) is None:
+                        missing.add("%s %s" % (key, network[key]))
+        if missing:
+            raise ConfigError("not found: %s" % ", ".join(sorted(missing)))
+
+    def check_refs(key: str, get):
+        refs = resolve_instance_refs(cfg, key, get)
+        missing = set()
+        for pool in cfg["pools"]:
+            values = pool["instance"][key]
+            for ref in values if isinstance(values, list) else [values]:
+                if ref not in refs:
+                    missing.add(ref)
+        if missing:
+            raise ConfigError("%s not found: %s" % (key, ", ".join(sorted(missing))))
+
+    if check("OpenStack authentication and endpoints", connect):
+        check("images", lambda: check_refs("image", CLOUD.get_image))
+        check("flavors", lambda: check_refs("flavor", CLOUD.get_flavor))
+        check("networks", check_networks)
+        check("keypairs", lambda: ensure_keypairs(read_only, CLOUD))
+        check("server groups", lambda: resolve_server_groups(read_only, CLOUD))
+
+    for github_name, github_cfg in get_github_configs(cfg).items():
+        check(
+            "GitHub runners of %s" % github_cfg["org"],
+            lambda github_name=github_name: list_runners(github_name),
+        )
+    check("runner groups", lambda: resolve_runner_groups(cfg, GITHUBS))
+
+    failed = [name for name, error in results if error is not None]
+    print(
+        "%d of %d checks passed%s"
+        % (
+            len(results) - len(failed),
+            len(results),
+            ", failed: %s" % ", ".join(failed) if failed else "",
+        )
+    )
+    return not failed
+
+
+def drain():
+    """Delete the servers of all runners which are not busy, on shutdown.
+
+    This only happens with ``drain_on_shutdown`` set, and gives up after
+    ``drain_timeout_secs``.  Busy runners are left alone to finish their jobs.
+    """
+    if not CFG.get("drain_on_shutdown", False):
+        return
+
+    if scheduler.running:
+        scheduler.pause()
+    app.logger.info("Draining runners which are not busy")
+    try:
+        with openstack_errors():
+            servers_by_key = {server_key(server): server for server in list_servers()}
+        runners = []
+        for name in get_github_configs(CFG):
+            runners.extend(list_runners(name))
+    except Error as e:
+        app.logger.error("Failed to drain runners: %s", e)
+        return
+
+    nodes = [
+        (servers_by_key[runner_key(runner)], runner)
+        for runner in runners
+        if not runner.busy and runner_key(runner) in servers_by_key
+    ]
+    executor = ThreadPoolExecutor(max_workers=CFG["openstack"].get("concurrency", 8))
+    futures = [
+        executor.submit(delete_node, server, runner, "shutdown")
+        for server, runner in nodes
+    ]
+    done, not_done = concurrent.futures.wait(
+        futures, timeout=CFG.get("drain_timeout_secs", 60)
+    )
+    executor.shutdown(wait=False, cancel_futures=True)
+
+    drained = sum(1 for future in done if not future.exception() and future.result())
+    app.logger.info(
+        "Drained %s of %s runners, %s timed out",
+        drained,
+        len(nodes),
+        len(not_done),
+    )
+
+
+def maybe_reload_config():
+    """Reload the configuration if requested by SIGHUP or if the file changed.
+
+    The new configuration is only swapped in once it is validated and all of
+    the names in it are resolved, otherwise the current one is kept.  The
+    OpenStack and GitHub clients are kept as long as their settings did not
+    change.
+    """
+    global CFG, CLOUD, GITHUBS, DRY_RUN, OPENSTACK_SEMAPHORE
+    global RUNNER_GROUPS, SERVER_GROUPS, IMAGES, FLAVORS, CONFIG_MTIME
+
+    mtime = os.path.getmtime(ARGS.config)
+    if not RELOAD_REQUESTED.is_set() and mtime == CONFIG_MTIME:
+        return
+    RELOAD_REQUESTED.clear()
+    CONFIG_MTIME = mtime
+
+    app.logger.info("Reloading configuration from %s", ARGS.config)
+    try:
+        cfg = load_config(ARGS.config, CONFIG_OVERRIDES)
+        apply_proxy_config(cfg)
+
+        cloud = CLOUD
+        if cfg["openstack"] != CFG["openstack"]:
+            cloud = connect_openstack(cfg)
+
+        githubs = {}
+        current = get_github_configs(CFG)
+        for name, github_cfg in get_github_configs(cfg).items():
+            if github_cfg == current.get(name):
+                githubs[name] = GITHUBS[name]
+            else:
+                githubs[name] = connect_github(github_cfg)
+
+        runner_groups = resolve_runner_groups(cfg, githubs)
+        server_groups = resolve_server_groups(cfg, cloud)
+        ensure_keypairs(cfg, cloud)
+        images = resolve_instance_refs(cfg, "image", cloud.get_image)
+        flavors = resolve_instance_refs(cfg, "flavor", cloud.get_flavor)
+    except Exception as e:
+        app.logger.error("Failed to reload configuration, keeping it: %s", e)
+        return
+
+    concurrency = cfg["openstack"].get("concurrency", 8)
+    if concurrency != CFG["openstack"].get("concurrency", 8):
+        OPENSTACK_SEMAPHORE = threading.BoundedSemaphore(concurrency)
+    CLOUD, GITHUBS = cloud, githubs
+    RUNNER_GROUPS, SERVER_GROUPS = runner_groups, server_groups
+    IMAGES, FLAVORS = images, flavors
+    DRY_RUN = cfg.get("dry_run", False)
+    CFG = cfg
+    setup_audit_log(CFG)
+
+
+if ARGS.preflight:
+    sys.exit(0 if preflight(CFG) else 1)
 
+startup()
 
 if __name__ == "__main__":
     app.run()
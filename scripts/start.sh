@@ -5,6 +5,12 @@ RUNNER_GROUP=${RUNNER_GROUP:-ubuntu}
 RUNNER_VERSION=${RUNNER_VERSION:-2.311.0}
 RUNNER_CHECKSUM=${RUNNER_CHECKSUM:-29fc8cf2dab4c195bb147384e7e2c94cfd4d4022c793b346a6175435265aa278}
 RUNNER_JITCONFIG=___JIT_CONFIG___
+RUNNER_CONFIG_ARGS=___CONFIG_ARGS___
+
+# Load the proxy settings, if any
+if [ -f /etc/profile.d/gha-proxy.sh ]; then
+  . /etc/profile.d/gha-proxy.sh
+fi
 
 # Download the runner package
 mkdir -p /opt/github/actions-runner/${RUNNER_VERSION}
@@ -17,5 +23,13 @@ chown -R ${RUNNER_USER}:${RUNNER_GROUP} /opt/github/actions-runner
 # Add the runner user to the docker group
 usermod -aG docker ${RUNNER_USER}
 
+# Register the runner, unless it has a JIT config
+if [ -z "${RUNNER_JITCONFIG}" ]; then
+  su - ubuntu -c "/opt/github/actions-runner/${RUNNER_VERSION}/config.sh ${RUNNER_CONFIG_ARGS}"
+  RUNNER_ARGS=""
+else
+  RUNNER_ARGS="--jitconfig ${RUNNER_JITCONFIG}"
+fi
+
 # Start the runner
-su - ubuntu -c "/opt/github/actions-runner/${RUNNER_VERSION}/run.sh --jitconfig ${RUNNER_JITCONFIG}" &
+su - ubuntu -c "/opt/github/actions-runner/${RUNNER_VERSION}/run.sh ${RUNNER_ARGS}" &